@@ -52,5 +52,10 @@
         system = "x86_64-linux";
         modules = [];
       };
+      darwinConfigurations."${hostname}-secondary" = darwin.lib.darwinSystem {
+        inherit specialArgs;
+        system = "x86_64-linux";
+        modules = [];
+      };
     };
 }
@@ -0,0 +1,37 @@
+use std::path::Path;
+
+use tracing::Subscriber;
+use tracing_chrome::{ChromeLayer, ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Build a layer recording span timings to `path` in the Chrome tracing format.
+///
+/// The file is only complete once the returned guard is dropped.
+pub(crate) fn chrome_layer<S>(path: &Path) -> (ChromeLayer<S>, FlushGuard)
+where
+  S: Subscriber + for<'span> LookupSpan<'span> + Send + Sync,
+{
+  ChromeLayerBuilder::new().file(path).include_args(true).build()
+}
+
+#[cfg(test)]
+mod tests {
+  use tracing_subscriber::prelude::*;
+
+  use super::*;
+
+  #[test]
+  fn test_chrome_layer_writes_trace_file() -> color_eyre::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("trace.json");
+    let (layer, guard) = chrome_layer(&path);
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+      tracing::info_span!("build").in_scope(|| tracing::info!("building"));
+    });
+    drop(guard);
+
+    let trace: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+    assert!(trace.as_array().is_some_and(|events| !events.is_empty()), "{trace}");
+    Ok(())
+  }
+}
@@ -1,5 +1,5 @@
 use anstyle::Style;
-use clap::{builder::Styles, command, Args, Parser, Subcommand};
+use clap::{builder::Styles, Args, Parser, Subcommand};
 use clap_complete::Shell;
 
 fn make_style() -> Styles {
@@ -20,37 +20,362 @@ pub struct Cli {
   /// Rollback
   #[arg(long, global = true, conflicts_with("list_generations"))]
   pub rollback: bool,
+  /// Show the generation a rollback would switch to and its diff, without rolling back
+  #[arg(long, global = true, requires("rollback"))]
+  pub preview: bool,
   /// Profile name
   #[arg(short, long, global = true)]
   pub profile_name: Option<String>,
-  /// Flake
+  /// Flake, defaults to the content of `./.flake` if present
   #[arg(short, long, env = "FLAKE", global = true, value_hint = clap::ValueHint::DirPath)]
   pub flake: Option<String>,
+  /// Discover the flake from the current git repository when none is given
+  #[arg(long, global = true)]
+  pub auto_flake: bool,
+  /// Build the legacy `<darwin>` configuration, ignoring `--flake` and `$FLAKE`
+  #[arg(long, global = true)]
+  pub no_flake: bool,
+  /// Hostname of the configuration to build, can be repeated with the build action
+  #[arg(long, global = true)]
+  pub hostname: Vec<String>,
+  /// Use the fully qualified hostname instead of the short one when falling back to the local hostname
+  #[arg(long, global = true)]
+  pub fqdn: bool,
+  /// Force substituting build products from binary caches
+  #[arg(long, global = true, overrides_with("no_substitute"))]
+  pub substitute: bool,
+  /// Build everything locally without substituting from binary caches
+  #[arg(long, global = true, overrides_with("substitute"))]
+  pub no_substitute: bool,
+  /// Force building in the nix sandbox
+  #[arg(long, global = true, overrides_with("no_sandbox"))]
+  pub sandbox: bool,
+  /// Build outside of the nix sandbox
+  #[arg(long, global = true, overrides_with("sandbox"))]
+  pub no_sandbox: bool,
+  /// Use the nix evaluation cache
+  #[arg(long, global = true, overrides_with("no_eval_cache"))]
+  pub eval_cache: bool,
+  /// Evaluate without the nix evaluation cache, when it returns stale results
+  #[arg(long, global = true, overrides_with("eval_cache"))]
+  pub no_eval_cache: bool,
+  /// Don't let nix access the network
+  #[arg(long, global = true)]
+  pub offline: bool,
+  /// Accept the nix configuration of the flake without prompting
+  #[arg(long, global = true)]
+  pub accept_flake_config: bool,
+  /// Evaluate in pure mode, forbidding access to files outside the store
+  #[arg(long, global = true, conflicts_with("impure"))]
+  pub pure_eval: bool,
+  /// Allow access to mutable paths and repositories during evaluation
+  #[arg(long, global = true)]
+  pub impure: bool,
+  /// Override the flake registry entry NAME with FLAKE, can be repeated
+  #[arg(long, global = true, num_args = 2, value_names = ["NAME", "FLAKE"], action = clap::ArgAction::Append)]
+  pub override_flake: Vec<String>,
+  /// Use the inputs of FLAKE's lock file to resolve the flake registry references
+  #[arg(long, global = true, value_name = "FLAKE")]
+  pub inputs_from: Option<String>,
+  /// Resolve flake references against the registry file at PATH instead of the global one
+  #[arg(long, global = true, value_name = "PATH")]
+  pub flake_registry: Option<std::path::PathBuf>,
+  /// Cache the resolved flake metadata in the JSON file at PATH
+  #[arg(long, global = true, value_name = "PATH")]
+  pub cache_flake_metadata: Option<std::path::PathBuf>,
+  /// Seconds the cached flake metadata stays valid
+  #[arg(long, global = true, default_value_t = 3600, value_name = "SECONDS", requires = "cache_flake_metadata")]
+  pub cache_flake_metadata_ttl: u64,
+  /// Refetch the flake and its inputs, bypassing the flake metadata cache
+  #[arg(long, global = true)]
+  pub refresh: bool,
+  /// Build a local flake with uncommitted changes without warning about them
+  #[arg(long, global = true)]
+  pub allow_dirty: bool,
+  /// Build with plain nix instead of piping the logs through nom, the logs are rendered compactly
+  #[arg(long, global = true)]
+  pub no_nom: bool,
+  /// Fail when nom is missing instead of falling back to plain nix logs
+  #[arg(long, global = true, conflicts_with("no_nom"))]
+  pub strict_nom: bool,
+  /// Quickest feedback: build without nom, skip the nvd diff and print the out path
+  #[arg(long, global = true, conflicts_with("diff_summary"))]
+  pub fast: bool,
+  /// Only print the out path on stdout, implies `--no-nom` and `--no-nvd`
+  #[arg(long, global = true, conflicts_with_all(["diff_summary", "json"]))]
+  pub out_path_only: bool,
+  /// Skip the nvd diff after building
+  #[arg(long, global = true, conflicts_with("diff_summary"))]
+  pub no_nvd: bool,
+  /// Print a summary of the package changes after the diff
+  #[arg(long, global = true)]
+  pub diff_summary: bool,
+  /// Builders to use for distributed builds, forwarded to nix as `--builders`
+  #[arg(long, global = true)]
+  pub builders: Option<String>,
+  /// Additional binary cache to use for this build, can be repeated
+  #[arg(long, global = true, value_name = "URL")]
+  pub extra_substituters: Vec<String>,
+  /// Additional public key trusted to sign binary cache paths, can be repeated
+  #[arg(long, global = true, value_name = "KEY")]
+  pub extra_trusted_public_keys: Vec<String>,
+  /// Binary cache that unprivileged users may use, can be repeated
+  #[arg(long, global = true, value_name = "URL", value_parser = parse_substituter_url)]
+  pub trusted_substituters: Vec<String>,
+  /// URI of the nix store to build in
+  #[arg(long, global = true, value_name = "URI")]
+  pub store: Option<String>,
+  /// URI of the nix store to evaluate in, e.g. `auto`
+  #[arg(long, global = true, value_name = "URI")]
+  pub eval_store: Option<String>,
+  /// System type to build for, e.g. `aarch64-darwin`
+  #[arg(long, global = true, value_name = "DOUBLE")]
+  pub system: Option<String>,
+  /// Space separated system features of the builds, e.g. `big-parallel`
+  #[arg(long, global = true, value_name = "FEATURES")]
+  pub system_features: Option<String>,
+  /// Maximum number of build jobs run in parallel by nix, `0` disables local builds
+  #[arg(long, short = 'j', global = true)]
+  pub max_jobs: Option<u32>,
+  /// Number of cores used by each build job, `0` uses all available cores
+  #[arg(long, global = true)]
+  pub cores: Option<u32>,
+  /// Maximum number of seconds a single derivation may take to build, forwarded to nix as `--timeout`
+  #[arg(long, global = true, value_name = "SECS")]
+  pub build_timeout: Option<u64>,
+  /// Maximum number of seconds a derivation may build without producing output
+  #[arg(long, global = true, value_name = "SECS")]
+  pub max_silent_time: Option<u64>,
+  /// Warn when the build produced no output for SECS seconds, `0` disables the warning
+  #[arg(long, global = true, value_name = "SECS", default_value_t = 120)]
+  pub stall_warning: u64,
+  /// Pass a JSON value as the nix argument NAME, can be repeated
+  #[arg(long, global = true, num_args = 2, value_names = ["NAME", "JSON"], action = clap::ArgAction::Append)]
+  pub arg_json: Vec<String>,
+  /// Pass the nix expression in PATH as the nix argument NAME, can be repeated
+  #[arg(long, global = true, num_args = 2, value_names = ["NAME", "PATH"], action = clap::ArgAction::Append)]
+  pub arg_from_file: Vec<String>,
+  /// Number of `-v` flags forwarded to nix, independent of `--verbose`
+  #[arg(long, global = true, value_name = "N", default_value_t = 0)]
+  pub verbose_nix: u8,
+  /// Command to run before building, the run is aborted if it fails
+  #[arg(long, global = true, value_hint = clap::ValueHint::CommandString)]
+  pub pre_build: Option<String>,
+  /// Number of times nix commands are retried on transient network failures
+  #[arg(long, global = true, default_value_t = 0)]
+  pub retries: u32,
+  /// System configuration to activate instead of the one darwin-rebuild is installed in
+  #[arg(long, global = true, value_hint = clap::ValueHint::DirPath)]
+  pub system_config: Option<String>,
+  /// Update a single flake input in the lock file before switching
+  #[arg(long, global = true, value_name = "INPUT")]
+  pub flake_update_input: Option<String>,
+  /// Commit the updated flake lock file, only for local flakes
+  #[arg(long, global = true, requires = "flake_update_input")]
+  pub commit_lock: bool,
+  /// Number of generations to keep after a successful switch, older ones are deleted
+  #[arg(long, global = true, value_name = "N")]
+  pub keep: Option<usize>,
+  /// Delete the generations older than AGE after a successful switch, e.g. `30d`
+  #[arg(long, global = true, value_name = "AGE", value_parser = parse_generation_age)]
+  pub keep_since: Option<String>,
+  /// Environment variables kept when the system activation runs under sudo, e.g. `HOMEBREW_NO_AUTO_UPDATE,HTTPS_PROXY`
+  #[arg(long, global = true, value_name = "VARS", value_delimiter = ',', value_parser = parse_env_var_name)]
+  pub keep_env_vars: Vec<String>,
+  /// Program asking for the sudo password, passed to sudo as `SUDO_ASKPASS` with `-A`
+  #[arg(long, global = true, value_name = "PATH", value_hint = clap::ValueHint::FilePath)]
+  pub sudo_askpass: Option<std::path::PathBuf>,
+  /// Seconds to wait for another run changing the profile to finish before bailing
+  #[arg(long, global = true, value_name = "SECS", default_value_t = 60)]
+  pub lock_timeout: u64,
+  /// Note describing the generation created by the switch, shown by `generations`
+  #[arg(long, global = true, value_name = "MESSAGE")]
+  pub message: Option<String>,
+  /// Write a JSON report of the run to PATH, even when it fails
+  #[arg(long, global = true, value_name = "PATH", value_hint = clap::ValueHint::FilePath)]
+  pub report: Option<std::path::PathBuf>,
+  /// Print machine-readable JSON output on stdout
+  #[arg(long, global = true)]
+  pub json: bool,
+  /// Keep a `result` symlink to the latest build in the state directory
+  #[arg(long, global = true)]
+  pub keep_result: bool,
+  /// After building, show why the system configuration depends on PKG
+  #[arg(long, global = true, value_name = "PKG")]
+  pub why_depends: Option<String>,
+  /// After building, print the total size of the system closure
+  #[arg(long, global = true)]
+  pub closure_size: bool,
+  /// Print the closure diff with `nix store diff-closures` instead of nvd
+  #[arg(long, global = true)]
+  pub store_diff: bool,
+  /// Rebuild once with full logs and `--show-trace` when the build fails
+  #[arg(long, global = true)]
+  pub verbose_failures: bool,
+  /// Ring the terminal bell when a run takes longer than `--bell-threshold`
+  #[arg(long, global = true)]
+  pub bell: bool,
+  /// Seconds a run has to take before `--bell` rings
+  #[arg(long, global = true, default_value_t = 30, value_name = "SECONDS", requires = "bell")]
+  pub bell_threshold: u64,
+  /// Print the resolved flake attribute of the configuration and exit without building
+  #[arg(long, global = true)]
+  pub print_flake_attr: bool,
+  /// Hide the nix build logs, only printing the diff and a one-line result
+  #[arg(long, global = true, conflicts_with("json"))]
+  pub summary_only: bool,
+  /// Build without any result symlink and print the out path
+  #[arg(long, global = true)]
+  pub no_link: bool,
+  /// Activate the last successful build instead of the one darwin-rebuild is installed in
+  #[arg(long, global = true, conflicts_with("system_config"))]
+  pub last: bool,
+  /// Run the activation even if the system is already active, or overwrite an existing flake with `init`
+  #[arg(long, global = true)]
+  pub force: bool,
+  /// Re-execute darwin-rebuild as root, so sudo only prompts once
+  #[arg(long, global = true)]
+  pub reexec_as_root: bool,
+  /// Only set the profile when switching, the activation is deferred to a later `activate`
+  #[arg(long, global = true)]
+  pub no_activate: bool,
+  /// Print the activation commands instead of running them, the profile is left unchanged
+  #[arg(long, global = true, conflicts_with("no_activate"))]
+  pub dry_activate: bool,
+  /// Record span timings to FILE in the Chrome tracing format
+  #[arg(long, global = true, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+  pub trace_file: Option<std::path::PathBuf>,
+  /// Prefix the debug logs with a timestamp
+  #[arg(long, global = true)]
+  pub log_timestamps: bool,
   /// Show debug logs
   #[arg(long, short, global = true)]
   pub verbose: bool,
+  /// Hide informational notices, such as the flake URL being rewritten by nix
+  #[arg(long, short, global = true, conflicts_with("verbose"))]
+  pub quiet: bool,
+}
+
+/// Accepts an age in days understood by `nix-env --delete-generations`, e.g. `30d`
+fn parse_generation_age(age: &str) -> Result<String, String> {
+  match age.strip_suffix('d').map(str::parse::<u32>) {
+    Some(Ok(days)) if days > 0 => Ok(age.to_string()),
+    _ => Err(format!("`{}` is not an age in days, e.g. 30d", age)),
+  }
+}
+
+/// Accepts the name of an environment variable, e.g. `HOMEBREW_NO_AUTO_UPDATE`
+fn parse_env_var_name(name: &str) -> Result<String, String> {
+  let mut chars = name.chars();
+  let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+  if valid {
+    Ok(name.to_string())
+  } else {
+    Err(format!("`{}` is not an environment variable name", name))
+  }
+}
+
+/// Accepts a binary cache URL with an `http`, `https`, `s3` or `file` scheme
+fn parse_substituter_url(url: &str) -> Result<String, String> {
+  const SCHEMES: [&str; 4] = ["http", "https", "s3", "file"];
+  let (scheme, rest) = url.split_once("://").ok_or(format!("`{}` is not a URL", url))?;
+  if !SCHEMES.contains(&scheme) {
+    return Err(format!("unsupported scheme `{}`, expected one of {}", scheme, SCHEMES.join(", ")));
+  }
+  if rest.is_empty() || rest.contains(char::is_whitespace) {
+    return Err(format!("`{}` is not a valid URL", url));
+  }
+  Ok(url.to_string())
+}
+
+impl Cli {
+  /// Returns the substitution mode, `None` if it is left to nix
+  pub fn substitute(&self) -> Option<bool> {
+    match (self.substitute, self.no_substitute) {
+      (true, _) => Some(true),
+      (_, true) => Some(false),
+      _ => None,
+    }
+  }
+
+  /// Returns the sandbox mode, `None` if it is left to nix
+  pub fn sandbox(&self) -> Option<bool> {
+    match (self.sandbox, self.no_sandbox) {
+      (true, _) => Some(true),
+      (_, true) => Some(false),
+      _ => None,
+    }
+  }
+
+  /// Returns the evaluation cache mode, `None` if it is left to nix
+  pub fn eval_cache(&self) -> Option<bool> {
+    match (self.eval_cache, self.no_eval_cache) {
+      (true, _) => Some(true),
+      (_, true) => Some(false),
+      _ => None,
+    }
+  }
+
+  /// Whether the run needs nix, only the actions generating files can do without it
+  pub fn needs_nix(&self) -> bool {
+    !matches!(self.action, Some(Action::Init | Action::Completions(_) | Action::Man(_)))
+  }
 }
 
 #[derive(Args, Debug, Eq, PartialEq, Clone, Copy)]
 pub struct BuildArgs {}
 
-#[derive(Subcommand, Default, Debug, Eq, PartialEq, Clone, Copy)]
+#[derive(Subcommand, Default, Debug, Eq, PartialEq, Clone)]
 pub enum Action {
   #[default]
+  #[command(visible_alias = "bu")]
   Build,
+  #[command(visible_alias = "ch")]
   Check,
+  #[command(visible_alias = "sw")]
   Switch,
   Edit,
   Activate,
   Changelog,
+  /// Print the current generation number of the profile
+  CurrentGeneration,
+  /// List the generations of the profile with the store path they point to
+  Generations,
+  /// List the named system profiles with their current generation
+  Profiles,
+  /// Print the diff between two generations of the profile
+  Compare(CompareArgs),
+  /// Write a minimal nix-darwin flake for this machine in the current directory
+  Init,
   #[clap(value_enum)]
   Completions(CompletionArgs),
+  /// Generate the roff man page
+  Man(ManArgs),
 }
 
 #[derive(Args, Debug, Eq, PartialEq, Clone, Copy)]
+pub struct CompareArgs {
+  /// The generation to diff from
+  pub from: u32,
+  /// The generation to diff to
+  pub to: u32,
+}
+
+#[derive(Args, Debug, Eq, PartialEq, Clone)]
 pub struct CompletionArgs {
   /// The shell to generate the completion script for
   pub shell: Shell,
+  /// Write the completion script into this directory instead of stdout
+  #[arg(long, short, value_hint = clap::ValueHint::DirPath)]
+  pub output: Option<std::path::PathBuf>,
+}
+
+#[derive(Args, Debug, Eq, PartialEq, Clone)]
+pub struct ManArgs {
+  /// Write the man page to this file instead of stdout
+  #[arg(long, short, value_hint = clap::ValueHint::FilePath)]
+  pub output: Option<std::path::PathBuf>,
 }
 
 #[cfg(test)]
@@ -66,6 +391,9 @@ mod tests {
   #[case::switch("switch", Action::Switch)]
   #[case::edit("edit", Action::Edit)]
   #[case::activate("activate", Action::Activate)]
+  #[case::current_generation("current-generation", Action::CurrentGeneration)]
+  #[case::init("init", Action::Init)]
+  #[case::profiles("profiles", Action::Profiles)]
   fn should_parse_cli_build(#[case] cmd: &str, #[case] action: Action) {
     use clap::Parser;
     let cli = Cli::parse_from([APP_NAME, cmd, "--verbose"]);
@@ -73,6 +401,74 @@ mod tests {
     assert_eq!(cli.action, Some(action));
   }
 
+  #[rstest]
+  #[case::table(&["generations"], false)]
+  #[case::json(&["generations", "--json"], true)]
+  fn should_parse_cli_generations(#[case] args: &[&str], #[case] json: bool) {
+    use clap::Parser;
+    let cli = Cli::parse_from([&[APP_NAME], args].concat());
+    assert_eq!(cli.action, Some(Action::Generations));
+    assert_eq!(cli.json, json);
+  }
+
+  #[test_log::test]
+  fn should_parse_cli_compare() {
+    use clap::Parser;
+    let cli = Cli::parse_from([APP_NAME, "compare", "42", "45"]);
+    assert_eq!(cli.action, Some(Action::Compare(CompareArgs { from: 42, to: 45 })));
+  }
+
+  #[test_log::test]
+  fn should_reject_pure_eval_with_impure() {
+    use clap::Parser;
+    let result = Cli::try_parse_from([APP_NAME, "build", "--pure-eval", "--impure"]);
+    assert_eq!(result.unwrap_err().kind(), clap::error::ErrorKind::ArgumentConflict);
+  }
+
+  #[rstest]
+  #[case::switch(&["switch"], true)]
+  #[case::rollback(&["--rollback"], true)]
+  #[case::init(&["init"], false)]
+  #[case::completions(&["completions", "zsh"], false)]
+  #[case::man(&["man"], false)]
+  fn should_need_nix_unless_generating_files(#[case] args: &[&str], #[case] needs_nix: bool) {
+    use clap::Parser;
+    let cli = Cli::parse_from([&[APP_NAME], args].concat());
+    assert_eq!(cli.needs_nix(), needs_nix);
+  }
+
+  #[rstest]
+  #[case::build("bu", Action::Build)]
+  #[case::check("ch", Action::Check)]
+  #[case::switch("sw", Action::Switch)]
+  fn should_parse_cli_aliases(#[case] cmd: &str, #[case] action: Action) {
+    use clap::Parser;
+    let cli = Cli::parse_from([APP_NAME, cmd]);
+    assert_eq!(cli.action, Some(action));
+  }
+
+  #[rstest]
+  #[case::unset(&[], None)]
+  #[case::sandbox(&["--sandbox"], Some(true))]
+  #[case::no_sandbox(&["--no-sandbox"], Some(false))]
+  #[case::last_wins(&["--no-sandbox", "--sandbox"], Some(true))]
+  fn should_parse_cli_sandbox(#[case] args: &[&str], #[case] expected: Option<bool>) {
+    use clap::Parser;
+    let cli = Cli::parse_from([&[APP_NAME, "build"], args].concat());
+    assert_eq!(cli.sandbox(), expected);
+  }
+
+  #[rstest]
+  #[case::unset(&[], None)]
+  #[case::eval_cache(&["--eval-cache"], Some(true))]
+  #[case::no_eval_cache(&["--no-eval-cache"], Some(false))]
+  #[case::last_wins(&["--eval-cache", "--no-eval-cache"], Some(false))]
+  fn should_parse_cli_eval_cache(#[case] args: &[&str], #[case] expected: Option<bool>) {
+    use clap::Parser;
+    let cli = Cli::parse_from([&[APP_NAME, "build"], args].concat());
+    assert_eq!(cli.eval_cache(), expected);
+  }
+
   #[test]
   fn should_parse_cli_list_generations() {
     use clap::Parser;
@@ -81,6 +477,73 @@ mod tests {
     assert!(cli.list_generations);
   }
 
+  #[rstest]
+  #[case::unset(&[], None)]
+  #[case::substitute(&["--substitute"], Some(true))]
+  #[case::no_substitute(&["--no-substitute"], Some(false))]
+  #[case::last_wins(&["--substitute", "--no-substitute"], Some(false))]
+  fn should_parse_cli_substitute(#[case] args: &[&str], #[case] expected: Option<bool>) {
+    use clap::Parser;
+    let cli = Cli::parse_from([&[APP_NAME, "build"], args].concat());
+    assert_eq!(cli.substitute(), expected);
+  }
+
+  #[rstest]
+  #[case::https("https://cache.example.org")]
+  #[case::http("http://10.0.0.2:5000")]
+  #[case::s3("s3://nix-cache?region=eu-west-1")]
+  #[case::file("file:///var/cache/nix")]
+  fn should_parse_cli_trusted_substituters(#[case] url: &str) {
+    use clap::Parser;
+    let cli = Cli::parse_from([APP_NAME, "build", "--trusted-substituters", url]);
+    assert_eq!(cli.trusted_substituters, [url]);
+  }
+
+  #[rstest]
+  #[case::not_a_url("cache.example.org")]
+  #[case::unsupported_scheme("ftp://cache.example.org")]
+  #[case::empty_host("https://")]
+  #[case::whitespace("https://cache.example.org https://other")]
+  fn should_reject_invalid_trusted_substituters(#[case] url: &str) {
+    use clap::Parser;
+    let result = Cli::try_parse_from([APP_NAME, "build", "--trusted-substituters", url]);
+    assert_eq!(result.unwrap_err().kind(), clap::error::ErrorKind::ValueValidation);
+  }
+
+  #[rstest]
+  #[case::valid("30d", true)]
+  #[case::zero("0d", false)]
+  #[case::no_unit("30", false)]
+  #[case::other_unit("4w", false)]
+  #[case::negative("-1d", false)]
+  fn should_parse_cli_keep_since(#[case] age: &str, #[case] valid: bool) {
+    use clap::Parser;
+    let result = Cli::try_parse_from([APP_NAME, "switch", "--keep-since", age]);
+    match result {
+      Ok(cli) => assert_eq!(cli.keep_since.as_deref(), valid.then_some(age)),
+      Err(err) => assert!(!valid, "{err}"),
+    }
+  }
+
+  #[rstest]
+  #[case::single(&["--keep-env-vars", "HOMEBREW_NO_AUTO_UPDATE"], &["HOMEBREW_NO_AUTO_UPDATE"])]
+  #[case::list(&["--keep-env-vars", "HOMEBREW_NO_AUTO_UPDATE,HTTPS_PROXY"], &["HOMEBREW_NO_AUTO_UPDATE", "HTTPS_PROXY"])]
+  #[case::repeated(&["--keep-env-vars", "A", "--keep-env-vars", "_B"], &["A", "_B"])]
+  fn should_parse_cli_keep_env_vars(#[case] args: &[&str], #[case] expected: &[&str]) {
+    use clap::Parser;
+    let cli = Cli::parse_from([&[APP_NAME, "switch"], args].concat());
+    assert_eq!(cli.keep_env_vars, expected);
+  }
+
+  #[rstest]
+  #[case::assignment("A=1")]
+  #[case::digit("1A")]
+  #[case::empty("")]
+  fn should_reject_invalid_keep_env_vars(#[case] name: &str) {
+    use clap::Parser;
+    assert!(Cli::try_parse_from([APP_NAME, "switch", "--keep-env-vars", name]).is_err());
+  }
+
   #[test]
   fn should_parse_cli_rollback() {
     use clap::Parser;
@@ -88,4 +551,13 @@ mod tests {
     assert_eq!(cli.action, None);
     assert!(cli.rollback);
   }
+
+  #[test]
+  fn should_parse_cli_rollback_preview() {
+    use clap::Parser;
+    let cli = Cli::parse_from([APP_NAME, "--rollback", "--preview"]);
+    assert!(cli.rollback && cli.preview);
+    let result = Cli::try_parse_from([APP_NAME, "--preview"]);
+    assert_eq!(result.unwrap_err().kind(), clap::error::ErrorKind::MissingRequiredArgument);
+  }
 }
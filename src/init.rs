@@ -0,0 +1,93 @@
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+use color_eyre::{
+  eyre::{bail, eyre},
+  owo_colors::OwoColorize,
+  Section,
+};
+use log::info;
+
+type Result<T> = color_eyre::Result<T>;
+
+const FLAKE_TEMPLATE: &str = r#"{
+  description = "nix-darwin configuration";
+
+  inputs = {
+    nixpkgs.url = "github:nixos/nixpkgs/nixpkgs-unstable";
+    darwin = {
+      url = "github:lnl7/nix-darwin";
+      inputs.nixpkgs.follows = "nixpkgs";
+    };
+  };
+
+  outputs = {
+    self,
+    nixpkgs,
+    darwin,
+  }: {
+    darwinConfigurations."@hostname@" = darwin.lib.darwinSystem {
+      modules = [
+        {
+          # TODO replace with the platform of your machine, x86_64-darwin for Intel
+          nixpkgs.hostPlatform = "aarch64-darwin";
+          system.configurationRevision = self.rev or self.dirtyRev or null;
+          system.stateVersion = 5;
+        }
+      ];
+    };
+  };
+}
+"#;
+
+/// Render a minimal nix-darwin flake with a configuration for `hostname`
+pub fn flake_template(hostname: &str) -> String { FLAKE_TEMPLATE.replace("@hostname@", hostname) }
+
+/// Write a minimal nix-darwin `flake.nix` in `dir`, an existing file is only overwritten with `force`
+pub fn write_flake(dir: &Path, hostname: &str, force: bool) -> Result<PathBuf> {
+  let file = dir.join("flake.nix");
+  if file.exists() && !force {
+    return Err(eyre!("{} already exists", file.display())).suggestion("use --force to overwrite it");
+  }
+  if !dir.is_dir() {
+    bail!("{} is not a directory", dir.display());
+  }
+  fs::write(&file, flake_template(hostname))?;
+  info!("wrote {} with the configuration {}", file.display().yellow(), hostname.purple());
+  Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_str_eq;
+
+  use super::*;
+
+  #[test_log::test]
+  fn test_flake_template_uses_hostname() {
+    assert!(flake_template("my-mac").contains(r#"darwinConfigurations."my-mac""#));
+  }
+
+  #[test_log::test]
+  fn test_write_flake() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let file = write_flake(dir.path(), "my-mac", false)?;
+    assert_eq!(file, dir.path().join("flake.nix"));
+    assert_str_eq!(fs::read_to_string(file)?, flake_template("my-mac"));
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_write_flake_refuses_to_overwrite() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    fs::write(dir.path().join("flake.nix"), "{}")?;
+    assert!(write_flake(dir.path(), "my-mac", false).is_err());
+    assert_str_eq!(fs::read_to_string(dir.path().join("flake.nix"))?, "{}");
+
+    write_flake(dir.path(), "my-mac", true)?;
+    assert_str_eq!(fs::read_to_string(dir.path().join("flake.nix"))?, flake_template("my-mac"));
+    Ok(())
+  }
+}
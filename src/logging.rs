@@ -1,5 +1,8 @@
+use std::path::Path;
+
 use color_eyre::owo_colors::OwoColorize;
 use tracing::{Event, Level, Subscriber};
+use tracing_chrome::FlushGuard;
 use tracing_subscriber::{
   fmt,
   fmt::{FormatEvent, FormatFields},
@@ -41,18 +44,19 @@ where
   }
 }
 
-pub(crate) fn setup_logging(verbose: bool) -> color_eyre::Result<()> {
+pub(crate) fn setup_logging(
+  verbose: bool, timestamps: bool, trace_file: Option<&Path>,
+) -> color_eyre::Result<Option<FlushGuard>> {
   use tracing_subscriber::{
     filter::{filter_fn, FilterExt},
     prelude::*,
     EnvFilter,
   };
 
-  let layer_debug = fmt::layer()
-    .with_writer(std::io::stderr)
-    .without_time()
-    .compact()
-    .with_line_number(true)
+  let layer_debug = fmt::layer().with_writer(std::io::stderr).compact().with_line_number(true);
+  let layer_debug =
+    if timestamps { layer_debug.with_timer(fmt::time::SystemTime).boxed() } else { layer_debug.without_time().boxed() };
+  let layer_debug = layer_debug
     .with_filter(EnvFilter::from_default_env().or(filter_fn(move |_| verbose)))
     .with_filter(filter_fn(|meta| *meta.level() > Level::INFO));
 
@@ -67,9 +71,17 @@ pub(crate) fn setup_logging(verbose: bool) -> color_eyre::Result<()> {
       (level == Level::INFO) || (level == Level::WARN)
     }));
 
-  tracing_subscriber::registry().with(layer_debug).with(layer_info).init();
+  let (layer_trace, guard) = match trace_file {
+    Some(trace_file) => {
+      let (layer, guard) = crate::chrome_trace::chrome_layer(trace_file);
+      (Some(layer), Some(guard))
+    },
+    None => (None, None),
+  };
+
+  tracing_subscriber::registry().with(layer_debug).with(layer_info).with(layer_trace).init();
 
   tracing::trace!("Logging OK");
 
-  Ok(())
+  Ok(guard)
 }
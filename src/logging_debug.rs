@@ -1,4 +1,70 @@
-pub(crate) fn setup_logging(_verbose: bool) -> color_eyre::Result<()> {
+use std::path::Path;
+
+use pretty_env_logger::env_logger::Builder;
+use tracing_chrome::FlushGuard;
+
+/// Build the logger, filtered by `RUST_LOG` like [`pretty_env_logger::try_init`]
+fn builder(timestamps: bool) -> Builder {
+  let mut builder =
+    if timestamps { pretty_env_logger::formatted_timed_builder() } else { pretty_env_logger::formatted_builder() };
+  if let Ok(filters) = std::env::var("RUST_LOG") {
+    builder.parse_filters(&filters);
+  }
+  builder
+}
+
+pub(crate) fn setup_logging(
+  _verbose: bool, timestamps: bool, trace_file: Option<&Path>,
+) -> color_eyre::Result<Option<FlushGuard>> {
   use color_eyre::Section;
-  pretty_env_logger::try_init().map_err(|e| color_eyre::eyre::eyre!("unable to setup logging").with_error(|| e))
+  builder(timestamps).try_init().map_err(|e| color_eyre::eyre::eyre!("unable to setup logging").with_error(|| e))?;
+
+  let Some(trace_file) = trace_file else {
+    return Ok(None);
+  };
+  use tracing_subscriber::prelude::*;
+  let (layer_trace, guard) = crate::chrome_trace::chrome_layer(trace_file);
+  tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer_trace))
+    .map_err(|e| color_eyre::eyre::eyre!("unable to setup the trace file").with_error(|| e))?;
+  Ok(Some(guard))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+  };
+
+  use log::Log;
+  use pretty_env_logger::env_logger::Target;
+  use rstest::rstest;
+
+  use super::*;
+
+  #[derive(Clone, Default)]
+  struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { self.0.lock().unwrap().write(buf) }
+
+    fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+  }
+
+  #[rstest]
+  #[case::with_timestamps(true)]
+  #[case::without_timestamps(false)]
+  fn test_builder_timestamps(#[case] timestamps: bool) {
+    let buffer = Buffer::default();
+    let logger =
+      builder(timestamps).filter_level(log::LevelFilter::Debug).target(Target::Pipe(Box::new(buffer.clone()))).build();
+    logger.log(&log::Record::builder().level(log::Level::Debug).args(format_args!("building")).build());
+    logger.flush();
+
+    let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let output = strip_ansi_escapes::strip_str(output);
+    let timestamp = regex::Regex::new(r"^\s*\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}").unwrap();
+    assert_eq!(timestamp.is_match(&output), timestamps, "{output:?}");
+    assert!(output.contains("building"), "{output:?}");
+  }
 }
@@ -1,13 +1,24 @@
+mod chrome_trace;
 pub mod cli;
+pub mod init;
 pub mod initialize_panic_handler;
 #[cfg_attr(debug_assertions, path = "logging_debug.rs")]
 #[cfg_attr(not(debug_assertions), path = "logging.rs")]
 pub mod logging;
 pub mod macros;
+pub mod metadata_cache;
 pub mod nix_commands;
+pub mod nix_log;
+pub mod profile_lock;
+pub mod progress;
+pub mod reexec;
 mod runner;
+pub mod state;
+pub mod watchdog;
 
 const DEFAULT_PROFILE: &str = "/nix/var/nix/profiles/system";
+const CURRENT_SYSTEM: &str = "/run/current-system";
+const SYSTEM_PROFILES: &str = "/nix/var/nix/profiles/system-profiles";
 
 fn main() -> color_eyre::Result<()> {
   use clap::Parser;
@@ -16,8 +27,17 @@ fn main() -> color_eyre::Result<()> {
   initialize_panic_handler::initialize_panic_handler()?;
 
   let args = cli::Cli::parse();
-  logging::setup_logging(args.verbose)?;
+  // Flushes the trace file when dropped at the end of main
+  let trace_guard = logging::setup_logging(args.verbose, args.log_timestamps, args.trace_file.as_deref())?;
 
+  if let Some(code) = reexec::reexec_as_root(args.reexec_as_root, args.sudo_askpass.as_deref())? {
+    drop(trace_guard);
+    std::process::exit(code);
+  }
+
+  if args.needs_nix() {
+    nix_commands::ensure_nix_installed()?;
+  }
   let build_args = runner::nix_darwin_runner::NixDarwinRunner::new(&args)?;
   build_args.run()
 }
@@ -0,0 +1,121 @@
+use std::{
+  collections::HashMap,
+  fs,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::{eyre::eyre, owo_colors::OwoColorize, Section};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+type Result<T> = color_eyre::Result<T>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+  /// Seconds since the unix epoch when the metadata was resolved
+  fetched_at: u64,
+  metadata: Value,
+}
+
+/// Flake metadata persisted in a JSON file, keyed by the flake reference it was resolved from
+#[derive(Debug)]
+pub struct MetadataCache {
+  path: PathBuf,
+  ttl: Duration,
+  entries: HashMap<String, Entry>,
+}
+
+fn seconds_since_epoch(time: SystemTime) -> u64 { time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() }
+
+impl MetadataCache {
+  /// Load the cache from `path`, a missing or unreadable file is an empty cache
+  pub fn load(path: &Path, ttl: Duration) -> Self {
+    let entries = match fs::read_to_string(path) {
+      Ok(content) => {
+        serde_json::from_str(&content).unwrap_or_else(|e| {
+          warn!("ignoring the invalid flake metadata cache {}: {}", path.display().yellow(), e);
+          HashMap::new()
+        })
+      },
+      Err(e) => {
+        debug!("no flake metadata cache at {:?}: {}", path, e);
+        HashMap::new()
+      },
+    };
+    Self { path: path.to_path_buf(), ttl, entries }
+  }
+
+  /// Get the metadata of `flake` if it was resolved less than the TTL before `now`
+  pub fn get(&self, flake: &str, now: SystemTime) -> Option<&Value> {
+    let entry = self.entries.get(flake)?;
+    let age = seconds_since_epoch(now).saturating_sub(entry.fetched_at);
+    if age < self.ttl.as_secs() {
+      debug!("flake metadata cache hit for {} ({}s old)", flake.yellow(), age);
+      Some(&entry.metadata)
+    } else {
+      debug!("flake metadata cache expired for {} ({}s old)", flake.yellow(), age);
+      None
+    }
+  }
+
+  pub fn insert(&mut self, flake: &str, metadata: Value, now: SystemTime) {
+    self.entries.insert(flake.to_string(), Entry { fetched_at: seconds_since_epoch(now), metadata });
+  }
+
+  /// Write the cache back to its file
+  pub fn save(&self) -> Result<()> {
+    if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+      fs::create_dir_all(dir)?;
+    }
+    fs::write(&self.path, serde_json::to_string(&self.entries)?)
+      .map_err(|e| eyre!("unable to write the flake metadata cache {:?}", self.path).with_error(|| e))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  const TTL: Duration = Duration::from_secs(60);
+
+  fn metadata() -> Value { serde_json::json!({ "url": "github:owner/dotfiles" }) }
+
+  #[test_log::test]
+  fn test_write_then_hit_within_ttl() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("cache").join("flake-metadata.json");
+    let now = SystemTime::now();
+
+    let mut cache = MetadataCache::load(&path, TTL);
+    assert_eq!(cache.get("github:owner/dotfiles", now), None);
+    cache.insert("github:owner/dotfiles", metadata(), now);
+    cache.save()?;
+
+    let cache = MetadataCache::load(&path, TTL);
+    assert_eq!(cache.get("github:owner/dotfiles", now + Duration::from_secs(30)), Some(&metadata()));
+    assert_eq!(cache.get("github:owner/other", now), None);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_expires_after_ttl() {
+    let now = SystemTime::now();
+    let mut cache = MetadataCache::load(Path::new("/nonexistent/flake-metadata.json"), TTL);
+    cache.insert("github:owner/dotfiles", metadata(), now);
+    assert_eq!(cache.get("github:owner/dotfiles", now + TTL), None);
+  }
+
+  #[test_log::test]
+  fn test_invalid_file_is_empty() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("flake-metadata.json");
+    fs::write(&path, "not json")?;
+    let cache = MetadataCache::load(&path, TTL);
+    assert_eq!(cache.get("github:owner/dotfiles", SystemTime::now()), None);
+    Ok(())
+  }
+}
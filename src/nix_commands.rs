@@ -5,12 +5,15 @@ use color_eyre::{
   owo_colors::OwoColorize,
   Section, SectionExt,
 };
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use serde_json::Value;
 use subprocess::{Exec, Redirection};
 use tracing::debug_span;
 
-use crate::{print_bool, DEFAULT_PROFILE};
+use crate::{
+  print_bool,
+  watchdog::{StallWatchdog, WatchedReader},
+};
 
 type Result<T> = color_eyre::Result<T>;
 
@@ -20,8 +23,10 @@ pub(crate) trait ExecTrace {
 impl ExecTrace for Exec {
   fn trace(self) -> Self {
     let cmd = self.to_cmdline_lossy();
-    let split = cmd.split(' ').collect::<Vec<_>>();
-    let cmd = format!("{} {}", split[0].cyan(), split[1..].join(" ").yellow());
+    let cmd = match cmd.split_once(' ') {
+      Some((program, args)) => format!("{} {}", program.cyan(), args.yellow()),
+      None => cmd.cyan().to_string(),
+    };
     debug_span!("Running command {cmd}");
     debug!("Running command {cmd}");
 
@@ -29,17 +34,47 @@ impl ExecTrace for Exec {
   }
 }
 
-/// Get the current hostname
-pub fn get_local_hostname() -> Result<String> {
+/// Get the current hostname, only the short hostname unless `fqdn` is set
+pub fn get_local_hostname(fqdn: bool) -> Result<String> {
   let hostname = gethostname::gethostname()
     .into_string()
     .map_err(|e| eyre!("unable to get hostname").with_section(|| format!("{:?}", e)))
+    .map(|hostname| if fqdn { hostname } else { short_hostname(&hostname).to_string() })
     .inspect(|hostname| info!("Getting local hostname {}", hostname.purple().bold()));
 
   debug!("Local hostname: {hostname:?}");
   hostname
 }
 
+/// Get the short hostname, everything before the first dot
+pub fn short_hostname(hostname: &str) -> &str { hostname.split('.').next().unwrap_or(hostname) }
+
+/// Find an executable named `program` in the directories of `path`, formatted like `$PATH`
+pub fn find_executable(program: &str, path: Option<&OsStr>) -> Option<std::path::PathBuf> {
+  use std::os::unix::fs::PermissionsExt;
+
+  env::split_paths(path?).map(|dir| dir.join(program)).find(|file| {
+    fs::metadata(file).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+  })
+}
+
+/// Check that `nix` can be found in `path`, instead of failing later with a raw os error
+pub fn require_nix(path: Option<&OsStr>) -> Result<()> {
+  match find_executable("nix", path) {
+    Some(nix) => {
+      trace!("found nix at {}", nix.display());
+      Ok(())
+    },
+    None => {
+      Err(eyre!("nix is not installed or not in $PATH"))
+        .suggestion("install nix from https://nixos.org/download/ and open a new shell")
+    },
+  }
+}
+
+/// Check that `nix` can be found in `$PATH`
+pub fn ensure_nix_installed() -> Result<()> { require_nix(env::var_os("PATH").as_deref()) }
+
 /// Check if the nix command supports flake metadata
 pub fn nix_command_supports_flake_metadata<S>(flake_flags: &[S]) -> bool
 where
@@ -65,18 +100,153 @@ where
   MetadataFlags: AsRef<OsStr> + std::fmt::Debug,
 {
   debug!("Getting flake metadata {} {} {:?}", flake.cyan(), cmd.yellow(), extra_metadata_flags.yellow());
-  let output = Exec::cmd("nix")
-    .args(flake_flags)
-    .arg("flake")
-    .arg(cmd)
-    .arg("--json")
-    .args(extra_metadata_flags)
-    .arg("--")
-    .arg(flake)
-    .trace()
-    .capture()?;
+  let output =
+    flake_metadata_cmd(flake, cmd, flake_flags, extra_metadata_flags).trace().stderr(Redirection::Pipe).capture()?;
+
+  parse_flake_metadata_output(&output)
+}
+
+fn flake_metadata_cmd<FlakeFlags, MetadataFlags>(
+  flake: &(impl AsRef<OsStr> + ?Sized), cmd: &(impl AsRef<OsStr> + ?Sized), flake_flags: &[FlakeFlags],
+  extra_metadata_flags: &[MetadataFlags],
+) -> Exec
+where
+  FlakeFlags: AsRef<OsStr>,
+  MetadataFlags: AsRef<OsStr>,
+{
+  Exec::cmd("nix").args(flake_flags).arg("flake").arg(cmd).arg("--json").args(extra_metadata_flags).arg("--").arg(flake)
+}
+
+fn parse_flake_metadata_output(output: &subprocess::CaptureData) -> Result<Value> {
+  let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+  if !output.exit_status.success() {
+    error!("Stderr: {}", stderr);
+    return Err(eyre!(stderr).wrap_err(format!("nix flake metadata failed with {:?}", output.exit_status)));
+  }
+  serde_json::from_slice(&output.stdout).map_err(|e| {
+    let report = if stderr.is_empty() { eyre!(e) } else { eyre!(stderr).with_error(|| e) };
+    report.wrap_err("unable to parse flake metadata")
+  })
+}
+
+/// Run `op`, retrying up to `retries` times with an exponential backoff when it fails with a transient error
+pub fn retry<T>(retries: u32, op: impl FnMut() -> Result<T>) -> Result<T> {
+  retry_with_backoff(retries, std::time::Duration::from_secs(1), op)
+}
+
+fn retry_with_backoff<T>(
+  retries: u32, base_delay: std::time::Duration, mut op: impl FnMut() -> Result<T>,
+) -> Result<T> {
+  let mut attempt = 0;
+  loop {
+    match op() {
+      Ok(value) => return Ok(value),
+      Err(err) if attempt < retries && is_transient_failure(&format!("{:?}", err)) => {
+        let delay = base_delay * 2u32.pow(attempt);
+        attempt += 1;
+        warn!("transient failure, retrying in {:?} ({}/{})...", delay, attempt, retries);
+        std::thread::sleep(delay);
+      },
+      Err(err) => return Err(err),
+    }
+  }
+}
+
+/// Check if the output of a failed nix command looks like a transient network failure
+pub fn is_transient_failure(output: &str) -> bool {
+  const PATTERNS: &[&str] = &[
+    "unable to download",
+    "HTTP error",
+    "Could not resolve host",
+    "Couldn't connect to server",
+    "Connection timed out",
+    "Connection reset by peer",
+    "Timeout was reached",
+    "SSL connect error",
+  ];
+  PATTERNS.iter().any(|pattern| output.contains(pattern))
+}
+
+/// Discover a flake by walking up from `start` to the root of its git repository
+///
+/// Outside of a git repository only `start` itself is checked.
+pub fn discover_flake(start: &(impl AsRef<Path> + std::fmt::Debug)) -> Result<Option<String>> {
+  debug!("Discovering flake from {start:?}");
+  let start = fs::canonicalize(start)?;
+  let git_root = start.ancestors().find(|dir| dir.join(".git").exists());
+  let flake_dir = match git_root {
+    Some(git_root) => {
+      start.ancestors().take_while(|dir| dir.starts_with(git_root)).find(|dir| dir.join("flake.nix").is_file())
+    },
+    None => Some(start.as_path()).filter(|dir| dir.join("flake.nix").is_file()),
+  };
+  debug!("Discovered flake directory {flake_dir:?}");
+  flake_dir
+    .map(|dir| dir.to_str().map(|dir| format!("path:{}", dir)).ok_or(eyre!("unable to convert {dir:?} to a string")))
+    .transpose()
+}
+
+/// Read the flake reference from the `.flake` file in `dir`, if any
+pub fn read_flake_file(dir: &(impl AsRef<Path> + std::fmt::Debug)) -> Result<Option<String>> {
+  let file = dir.as_ref().join(".flake");
+  if !file.is_file() {
+    return Ok(None);
+  }
+  debug!("Reading flake from {file:?}");
+  let content = fs::read_to_string(&file)?;
+  Ok(content.lines().next().map(str::trim).filter(|flake| !flake.is_empty()).map(str::to_string))
+}
+
+/// Update a single input of the flake lock file
+pub fn flake_lock_update_input<FlakeFlags>(
+  flake: &(impl AsRef<OsStr> + std::fmt::Display + ?Sized), input: &(impl AsRef<OsStr> + std::fmt::Display + ?Sized),
+  flake_flags: &[FlakeFlags],
+) -> Result<()>
+where
+  FlakeFlags: AsRef<OsStr>,
+{
+  info!("updating flake input {}...", input.purple());
+  let status = flake_lock_update_input_cmd(flake, input, flake_flags).trace().join()?;
+  if status.success() {
+    Ok(())
+  } else {
+    bail!("Failed to update flake input {}", input);
+  }
+}
+
+fn flake_lock_update_input_cmd<FlakeFlags>(
+  flake: &(impl AsRef<OsStr> + ?Sized), input: &(impl AsRef<OsStr> + ?Sized), flake_flags: &[FlakeFlags],
+) -> Exec
+where
+  FlakeFlags: AsRef<OsStr>,
+{
+  Exec::cmd("nix").args(flake_flags).arg("flake").arg("lock").arg("--update-input").arg(input).arg("--").arg(flake)
+}
+
+/// Get the directory of a local flake, `None` if the flake is not a local path
+pub fn local_flake_dir(flake: &str) -> Option<&str> {
+  let flake = flake.split(['?', '#']).next().unwrap_or_default();
+  let dir = flake.strip_prefix("path:").or(flake.strip_prefix("git+file://")).unwrap_or(flake);
+  Some(dir).filter(|dir| dir.starts_with('/'))
+}
+
+/// Commit the flake lock file of the flake in `dir`
+pub fn git_commit_lockfile(dir: &(impl AsRef<OsStr> + std::fmt::Display + ?Sized), message: &str) -> Result<()> {
+  info!("committing flake.lock in {}...", dir.yellow());
+  for cmd in git_commit_lockfile_cmds(dir, message) {
+    let status = cmd.trace().join()?;
+    if !status.success() {
+      bail!("Failed to commit flake.lock in {}", dir);
+    }
+  }
+  Ok(())
+}
 
-  serde_json::from_slice(&output.stdout).map_err(|e| eyre!("unable to parse flake metadata").with_error(|| e))
+fn git_commit_lockfile_cmds(dir: &(impl AsRef<OsStr> + ?Sized), message: &str) -> [Exec; 2] {
+  [
+    Exec::cmd("git").arg("-C").arg(dir).arg("add").arg("flake.lock"),
+    Exec::cmd("git").arg("-C").arg(dir).arg("commit").arg("-m").arg(message).arg("--").arg("flake.lock"),
+  ]
 }
 
 pub fn nix_instantiate_find_file(file: &(impl AsRef<OsStr> + std::fmt::Debug + ?Sized)) -> Result<String> {
@@ -101,10 +271,35 @@ pub fn exec_editor(file: &impl AsRef<OsStr>) -> Result<()> {
   #[cfg(not(test))]
   {
     let editor = env::var("EDITOR").unwrap_or("vi".to_string());
-    Exec::cmd(editor).arg(file).trace().join().map(|_| ()).map_err(|e| eyre!("unable to open editor").with_error(|| e))
+    let editor = editor_command(&editor);
+    let (program, args) = editor.split_first().ok_or(eyre!("EDITOR is empty"))?;
+    Exec::cmd(program)
+      .args(args)
+      .arg(file)
+      .trace()
+      .join()
+      .map(|_| ())
+      .map_err(|e| eyre!("unable to open editor").with_error(|| e))
   }
 }
 
+/// Split the editor command, adding the flag making GUI editors wait for the file to be closed
+pub fn editor_command(editor: &str) -> Vec<String> {
+  let mut argv: Vec<String> = editor.split_whitespace().map(str::to_string).collect();
+  let program = argv.first().and_then(|program| Path::new(program).file_name()).and_then(|name| name.to_str());
+  let wait_flag = match program {
+    Some("code" | "code-insiders" | "codium" | "atom") => Some("--wait"),
+    Some("subl") => Some("-w"),
+    _ => None,
+  };
+  if let Some(wait_flag) = wait_flag {
+    if !argv.iter().any(|arg| arg == "--wait" || arg == "-w") {
+      argv.push(wait_flag.to_string());
+    }
+  }
+  argv
+}
+
 pub fn nix_edit<FlakeFlagsItems>(
   flake: &(impl AsRef<OsStr> + std::fmt::Display), flake_attr: &(impl AsRef<OsStr> + std::fmt::Display),
   flake_flags: &[FlakeFlagsItems],
@@ -120,29 +315,85 @@ where
 
 pub fn nix_build<BuildFlagsItems>(
   expression: &(impl AsRef<OsStr> + std::fmt::Display + ?Sized),
-  attr: &(impl AsRef<OsStr> + std::fmt::Display + ?Sized), out_dir: &(impl AsRef<str> + std::fmt::Display),
+  attr: &(impl AsRef<OsStr> + std::fmt::Display + ?Sized), out_link: Option<&str>,
   extra_build_flags: &[BuildFlagsItems],
-) -> Result<String>
+) -> Result<BuildOutcome>
 where
   BuildFlagsItems: AsRef<OsStr> + std::fmt::Debug,
 {
   debug!("Building the system configuration {} {} {:?}", expression.blue(), attr.yellow(), extra_build_flags.blue());
 
-  let args = vec!["--out-link", out_dir.as_ref()];
-  let output =
-    Exec::cmd("nix-build").arg(expression).args(extra_build_flags).args(&args).arg("-A").arg(attr).capture()?;
+  let output = nix_build_cmd(expression, attr, out_link, extra_build_flags).capture()?;
   let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
   if output.exit_status.success() {
-    Ok(stdout)
+    Ok(BuildOutcome { out_path: stdout, used_nom: false, diffed: false })
   } else {
     Err(eyre!("Failed to build the system configuration").with_section(|| stdout))
   }
 }
 
+fn nix_build_cmd<BuildFlagsItems>(
+  expression: &(impl AsRef<OsStr> + ?Sized), attr: &(impl AsRef<OsStr> + ?Sized), out_link: Option<&str>,
+  extra_build_flags: &[BuildFlagsItems],
+) -> Exec
+where
+  BuildFlagsItems: AsRef<OsStr>,
+{
+  let args = match out_link {
+    Some(out_link) => vec!["--out-link", out_link],
+    None => vec!["--no-out-link"],
+  };
+  Exec::cmd("nix-build").arg(expression).args(extra_build_flags).args(&args).arg("-A").arg(attr)
+}
+
+/// Get the attribute of the buildable system output, only appending `.system` when it is missing
+pub fn system_attr(flake_attr: &impl std::fmt::Display) -> String {
+  let flake_attr = flake_attr.to_string();
+  if flake_attr.ends_with(".system") {
+    flake_attr
+  } else {
+    format!("{}.system", flake_attr)
+  }
+}
+
+/// How the logs of a build are shown
+#[derive(Debug, Clone, Copy)]
+pub struct BuildLogs {
+  /// Pipe the logs through nom
+  pub nom: bool,
+  /// Keep the logs off the terminal, they are still used to report failures
+  pub quiet: bool,
+  /// Warn when the build prints nothing for this long
+  pub stall_warning: std::time::Duration,
+}
+
+/// A successful build of the system configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildOutcome {
+  /// The out link, or the store path when the result is not linked
+  pub out_path: String,
+  /// The build logs went through nom
+  pub used_nom: bool,
+  /// The closure diff with the current system was printed
+  pub diffed: bool,
+}
+
+/// Read the out path from the `--json` output of `nix build`
+fn flake_build_outcome(stdout: &[u8]) -> Result<BuildOutcome> {
+  let json_output: Value = serde_json::from_slice(stdout)?;
+  let out_path = json_output[0]["outputs"]["out"].as_str().map(|a| a.to_string()).ok_or(
+    eyre!("unable to get output").with_section(|| {
+      let stdout = String::from_utf8_lossy(stdout);
+      stdout.to_string().header("stdout: ")
+    }),
+  )?;
+  Ok(BuildOutcome { out_path, used_nom: false, diffed: false })
+}
+
 pub fn nix_flake_build<Attr, BuildFlagsItems>(
   flake: &(impl AsRef<OsStr> + std::fmt::Display), flake_attr: &(impl AsRef<OsStr> + std::fmt::Display),
-  flake_flags: &[Attr], out_dir: &(impl AsRef<str> + std::fmt::Display), extra_build_flags: &[BuildFlagsItems],
-) -> Result<String>
+  flake_flags: &[Attr], out_link: Option<&str>, extra_build_flags: &[BuildFlagsItems], logs: BuildLogs,
+) -> Result<BuildOutcome>
 where
   Attr: AsRef<OsStr> + std::fmt::Debug,
   BuildFlagsItems: AsRef<OsStr> + std::fmt::Debug,
@@ -155,66 +406,374 @@ where
     extra_build_flags.blue()
   );
 
-  let nom = true;
-  if nom {
-    let args = vec!["--out-link", out_dir.as_ref()];
+  // nom reads the logs from stdout, so the out path is only known through the out link
+  if let (true, Some(out_link)) = (logs.nom, out_link) {
+    let args = vec!["--out-link", out_link];
     let cmd = {
       Exec::cmd("nix")
         .args(flake_flags)
         .arg("build")
         .args(&["--log-format", "internal-json", "-v"])
         .args(&args)
-        .args(&extra_build_flags)
+        .args(extra_build_flags)
         .arg("--")
-        .arg(format!("{}#{}.system", flake, flake_attr))
+        .arg(format!("{}#{}", flake, system_attr(flake_attr)))
         .trace()
         .stdout(Redirection::Pipe)
         .stderr(Redirection::Merge)
         | Exec::cmd("nom").args(&["--json"])
     }
-    .stdout(Redirection::None);
+    .stdout(Redirection::Pipe);
 
-    let result = cmd.join()?;
-    trace!("Result: {:?}", result.yellow());
-    if result.success() {
-      debug!("build succedded, printing diff");
-      Exec::cmd("nvd").args(&["diff", DEFAULT_PROFILE, out_dir.as_ref()]).trace().join()?;
-
-      Ok(out_dir.as_ref().to_string())
+    let mut children = cmd.popen()?;
+    let watchdog = StallWatchdog::spawn(logs.stall_warning);
+    let output = match children.last_mut().and_then(|nom| nom.stdout.take()) {
+      Some(stdout) => tee_lines(WatchedReader::new(stdout, watchdog.as_ref()), &mut std::io::stderr())?,
+      None => String::new(),
+    };
+    drop(watchdog);
+    let statuses = children.iter_mut().map(|child| child.wait()).collect::<std::result::Result<Vec<_>, _>>()?;
+    trace!("Result: {:?}", statuses.yellow());
+    if statuses.iter().all(|status| status.success()) {
+      Ok(BuildOutcome { out_path: out_link.to_string(), used_nom: true, diffed: false })
     } else {
-      Err(eyre!("Failed to build the system configuration"))
+      let failures = parse_build_failures(&output);
+      let errors = parse_build_errors(&output);
+      let mut report = eyre!("Failed to build the system configuration");
+      if !failures.is_empty() {
+        report = report.with_section(|| failures.join("\n").header("Failed derivations:"));
+      }
+      if !errors.is_empty() {
+        report = report.with_section(|| errors.join("\n").header("Errors:"));
+      }
+      Err(report)
     }
   } else {
-    let output = Exec::cmd("nix")
-      .args(flake_flags)
-      .arg("build")
-      .arg("--json")
-      .args(&extra_build_flags)
-      .arg("--")
-      .arg(format!("{}#{}.system", flake, flake_attr))
+    let mut process = nix_flake_build_cmd(flake, flake_attr, flake_flags, out_link, extra_build_flags)
+      .trace()
       .stdout(Redirection::Pipe)
       .stderr(Redirection::Pipe)
-      .capture()?;
-
-    if output.exit_status.success() {
-      let json_output: Value = serde_json::from_slice(&output.stdout)?;
+      .popen()?;
+    let watchdog = StallWatchdog::spawn(logs.stall_warning);
+    let log = match process.stderr.take() {
+      Some(stderr) => {
+        crate::nix_log::render_build_log(
+          WatchedReader::new(stderr, watchdog.as_ref()),
+          logs.quiet,
+          &mut std::io::stderr(),
+        )?
+      },
+      None => String::new(),
+    };
+    drop(watchdog);
+    let mut stdout = vec![];
+    if let Some(mut process_stdout) = process.stdout.take() {
+      std::io::Read::read_to_end(&mut process_stdout, &mut stdout)?;
+    }
+    let exit_status = process.wait()?;
 
-      json_output[0]["outputs"]["out"].as_str().map(|a| a.to_string()).ok_or(
-        eyre!("unable to get output").with_section(|| {
-          let stdout = String::from_utf8_lossy(&output.stdout);
-          stdout.to_string().header("stdout: ")
-        }),
-      )
+    if exit_status.success() {
+      flake_build_outcome(&stdout)
     } else {
-      let stdout = String::from_utf8_lossy(&output.stdout).to_string().header("stdout: ");
+      let stdout = String::from_utf8_lossy(&stdout).to_string().header("stdout: ");
       error!("Stdout: {}", stdout);
-      let stderr = String::from_utf8_lossy(&output.stderr).to_string().header("stderr: ");
+      let stderr = parse_build_errors(&log).join("\n").header("stderr: ");
       error!("Stderr: {}", stderr);
       Err(eyre!("Failed to run nix build")).with_section(|| stderr)
     }
   }
 }
 
+/// Arguments of `nix build` linking the result to `out_link`, or not linking it at all
+fn out_link_args(out_link: Option<&str>) -> Vec<&str> {
+  match out_link {
+    Some(out_link) => vec!["--out-link", out_link],
+    None => vec!["--no-link"],
+  }
+}
+
+fn nix_flake_build_verbose_cmd<Attr, BuildFlagsItems>(
+  flake: &impl std::fmt::Display, flake_attr: &impl std::fmt::Display, flake_flags: &[Attr], out_link: Option<&str>,
+  extra_build_flags: &[BuildFlagsItems],
+) -> Exec
+where
+  Attr: AsRef<OsStr>,
+  BuildFlagsItems: AsRef<OsStr>,
+{
+  Exec::cmd("nix")
+    .args(flake_flags)
+    .arg("build")
+    .args(&["-L", "--show-trace"])
+    .args(&out_link_args(out_link))
+    .args(extra_build_flags)
+    .arg("--")
+    .arg(format!("{}#{}", flake, system_attr(flake_attr)))
+}
+
+/// Build the system configuration again with the full build logs and the evaluation trace
+pub fn nix_flake_build_verbose<Attr, BuildFlagsItems>(
+  flake: &impl std::fmt::Display, flake_attr: &impl std::fmt::Display, flake_flags: &[Attr], out_link: Option<&str>,
+  extra_build_flags: &[BuildFlagsItems],
+) -> Result<()>
+where
+  Attr: AsRef<OsStr>,
+  BuildFlagsItems: AsRef<OsStr>,
+{
+  let status =
+    nix_flake_build_verbose_cmd(flake, flake_attr, flake_flags, out_link, extra_build_flags).trace().join()?;
+  if status.success() {
+    Ok(())
+  } else {
+    Err(eyre!("verbose build failed with {:?}", status))
+  }
+}
+
+/// Run `build`, running `verbose` once when it fails and `enabled` is set, the error of `build` is returned
+pub fn with_verbose_retry<T>(
+  enabled: bool, build: impl FnOnce() -> Result<T>, verbose: impl FnOnce() -> Result<()>,
+) -> Result<T> {
+  match build() {
+    Err(err) if enabled => {
+      warn!("build failed, rebuilding with full logs...");
+      if let Err(verbose_err) = verbose() {
+        debug!("verbose rebuild failed: {:?}", verbose_err);
+      }
+      Err(err)
+    },
+    result => result,
+  }
+}
+
+fn nix_flake_build_cmd<Attr, BuildFlagsItems>(
+  flake: &impl std::fmt::Display, flake_attr: &impl std::fmt::Display, flake_flags: &[Attr], out_link: Option<&str>,
+  extra_build_flags: &[BuildFlagsItems],
+) -> Exec
+where
+  Attr: AsRef<OsStr>,
+  BuildFlagsItems: AsRef<OsStr>,
+{
+  Exec::cmd("nix")
+    .args(flake_flags)
+    .arg("build")
+    .arg("--json")
+    .args(&["--log-format", "internal-json"])
+    .args(&out_link_args(out_link))
+    .args(extra_build_flags)
+    .arg("--")
+    .arg(format!("{}#{}", flake, system_attr(flake_attr)))
+}
+
+/// Copy every line read from `reader` to `out` while collecting them, `out` is stderr for the nom output so stdout
+/// stays free for the out path and the JSON summary
+fn tee_lines(reader: impl std::io::Read, out: &mut impl std::io::Write) -> Result<String> {
+  use std::io::BufRead;
+
+  let mut reader = std::io::BufReader::new(reader);
+  let mut output = String::new();
+  let mut line = vec![];
+  while reader.read_until(b'\n', &mut line)? > 0 {
+    out.write_all(&line)?;
+    output.push_str(&String::from_utf8_lossy(&line));
+    line.clear();
+  }
+  out.flush()?;
+  Ok(output)
+}
+
+/// Find the derivations reported as failed in the output of a build
+///
+/// Lines can either be nix `internal-json` messages (optionally prefixed by `@nix `), in which case only errors are
+/// considered, or plain text as rendered by `nom`.
+pub fn parse_build_failures(output: &str) -> Vec<String> {
+  let drv = regex::Regex::new(r"/nix/store/[0-9a-z]{32}-[^\s'\x22`]+\.drv").unwrap();
+  let mut failures: Vec<String> = vec![];
+  for line in output.lines() {
+    let line = strip_ansi_escapes::strip_str(line);
+    let message = match serde_json::from_str::<Value>(line.trim().trim_start_matches("@nix ")) {
+      Ok(json) if json["action"] == "msg" && json["level"] == 0 => json["msg"].as_str().unwrap_or_default().to_string(),
+      Ok(_) => continue,
+      Err(_) => line,
+    };
+    if !message.contains("failed") {
+      continue;
+    }
+    for path in drv.find_iter(&message).map(|m| m.as_str().to_string()) {
+      if !failures.contains(&path) {
+        failures.push(path);
+      }
+    }
+  }
+  failures
+}
+
+/// Find the error messages in the `internal-json` output of a build
+pub fn parse_build_errors(output: &str) -> Vec<String> {
+  output
+    .lines()
+    .filter_map(|line| serde_json::from_str::<Value>(line.trim().trim_start_matches("@nix ")).ok())
+    .filter(|json| json["action"] == "msg" && json["level"] == 0)
+    .filter_map(|json| json["msg"].as_str().map(strip_ansi_escapes::strip_str))
+    .collect()
+}
+
+/// Summary of the package changes reported by `nvd diff`
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct DiffSummary {
+  pub upgraded: usize,
+  pub downgraded: usize,
+  pub added: usize,
+  pub removed: usize,
+}
+
+impl std::fmt::Display for DiffSummary {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} upgraded, {} added, {} removed", self.upgraded, self.added, self.removed)?;
+    if self.downgraded > 0 {
+      write!(f, ", {} downgraded", self.downgraded)?;
+    }
+    Ok(())
+  }
+}
+
+/// Parse the output of `nvd diff` to count the changed packages
+pub fn parse_nvd_diff(output: &str) -> DiffSummary {
+  let output = strip_ansi_escapes::strip_str(output);
+  output.lines().map(str::trim_start).fold(DiffSummary::default(), |mut summary, line| {
+    match line.strip_prefix('[').and_then(|line| line.chars().next()) {
+      Some('U') => summary.upgraded += 1,
+      Some('D') => summary.downgraded += 1,
+      Some('A') => summary.added += 1,
+      Some('R') => summary.removed += 1,
+      _ => {},
+    }
+    summary
+  })
+}
+
+/// Print the differences between two system configurations with `nvd diff`
+///
+/// When `summarize` is set, the output is captured to also return a [`DiffSummary`].
+pub fn nvd_diff(
+  from: &(impl AsRef<OsStr> + std::fmt::Display + ?Sized), to: &(impl AsRef<OsStr> + std::fmt::Display + ?Sized),
+  summarize: bool,
+) -> Result<Option<DiffSummary>> {
+  debug!("Printing diff between {} and {}", from.yellow(), to.yellow());
+  let cmd = Exec::cmd("nvd").arg("diff").arg(from).arg(to).trace();
+  if !summarize {
+    cmd.join()?;
+    return Ok(None);
+  }
+
+  let output = cmd.stdout(Redirection::Pipe).capture()?;
+  let stdout = String::from_utf8_lossy(&output.stdout);
+  print!("{}", stdout);
+  Ok(Some(parse_nvd_diff(&stdout)))
+}
+
+fn closure_size_cmd<Flags>(out_path: &(impl AsRef<OsStr> + ?Sized), flags: &[Flags]) -> Exec
+where
+  Flags: AsRef<OsStr>,
+{
+  Exec::cmd("nix").args(flags).arg("path-info").arg("--closure-size").arg(out_path)
+}
+
+/// Parse the byte count of the last path printed by `nix path-info --closure-size`
+pub fn parse_closure_size(output: &str) -> Result<u64> {
+  let line = output.lines().rfind(|line| !line.trim().is_empty()).ok_or(eyre!("nix path-info printed nothing"))?;
+  let size = line.split_whitespace().last().unwrap_or_default();
+  size.parse().map_err(|e| eyre!("unable to parse the closure size {}", line.yellow()).with_error(|| e))
+}
+
+/// Format a byte count with binary units, e.g. `1.5 GiB`
+pub fn format_size(bytes: u64) -> String {
+  const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+  if bytes < 1024 {
+    return format!("{} B", bytes);
+  }
+  let mut size = bytes as f64 / 1024.0;
+  let mut unit = 0;
+  while size >= 1024.0 && unit < UNITS.len() - 1 {
+    size /= 1024.0;
+    unit += 1;
+  }
+  format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Get the size in bytes of the closure of `out_path`
+pub fn closure_size<Flags>(out_path: &(impl AsRef<OsStr> + std::fmt::Display + ?Sized), flags: &[Flags]) -> Result<u64>
+where
+  Flags: AsRef<OsStr>,
+{
+  debug!("Computing the closure size of {}", out_path.yellow());
+  let output = closure_size_cmd(out_path, flags).trace().stdout(Redirection::Pipe).capture()?;
+  if !output.exit_status.success() {
+    return Err(eyre!("nix path-info failed with {:?}", output.exit_status));
+  }
+  parse_closure_size(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn why_depends_cmd<Flags>(
+  out_path: &(impl AsRef<OsStr> + ?Sized), pkg: &(impl AsRef<OsStr> + ?Sized), flags: &[Flags],
+) -> Exec
+where
+  Flags: AsRef<OsStr>,
+{
+  Exec::cmd("nix").args(flags).arg("why-depends").arg(out_path).arg(pkg)
+}
+
+/// Print the dependency chain from a built system configuration to `pkg` with `nix why-depends`
+pub fn why_depends<Flags>(
+  out_path: &(impl AsRef<OsStr> + std::fmt::Display + ?Sized), pkg: &(impl AsRef<OsStr> + ?Sized), flags: &[Flags],
+) -> Result<()>
+where
+  Flags: AsRef<OsStr>,
+{
+  debug!("Looking for why {} depends on {}", out_path.yellow(), pkg.as_ref().to_string_lossy().yellow());
+  let status = why_depends_cmd(out_path, pkg, flags).trace().join()?;
+  if status.success() {
+    Ok(())
+  } else {
+    Err(eyre!("nix why-depends failed with {:?}", status))
+  }
+}
+
+fn store_diff_closures_cmd<Flags>(
+  old: &(impl AsRef<OsStr> + ?Sized), new: &(impl AsRef<OsStr> + ?Sized), flags: &[Flags],
+) -> Exec
+where
+  Flags: AsRef<OsStr>,
+{
+  Exec::cmd("nix").args(flags).arg("store").arg("diff-closures").arg(old).arg(new)
+}
+
+/// Print the differences between two closures with `nix store diff-closures`, which doesn't need nvd
+pub fn store_diff_closures<Flags>(
+  old: &(impl AsRef<OsStr> + std::fmt::Display + ?Sized), new: &(impl AsRef<OsStr> + std::fmt::Display + ?Sized),
+  flags: &[Flags],
+) -> Result<()>
+where
+  Flags: AsRef<OsStr>,
+{
+  debug!("Printing store diff between {} and {}", old.yellow(), new.yellow());
+  let status = store_diff_closures_cmd(old, new, flags).trace().join()?;
+  if status.success() {
+    Ok(())
+  } else {
+    Err(eyre!("nix store diff-closures failed with {:?}", status))
+  }
+}
+
+/// Run a hook command through the shell
+pub fn exec_hook(name: &str, command: &str) -> Result<()> {
+  info!("running {} hook {}...", name, command.yellow());
+  let status = Exec::shell(command).trace().join()?;
+  if status.success() {
+    Ok(())
+  } else {
+    Err(eyre!("Failed to run {} hook", name).with_section(|| command.to_string().header("command: ")))
+  }
+}
+
 pub fn is_root_user() -> Result<bool> {
   const USERNAME: &str = "root";
   debug!("Checking if the user is {}", USERNAME.bold().yellow());
@@ -222,22 +781,67 @@ pub fn is_root_user() -> Result<bool> {
   Ok(user == USERNAME)
 }
 
+/// Check if the path is read-only.
+///
+/// A path that doesn't exist yet (e.g. a profile on a fresh machine) is checked through its nearest existing
+/// ancestor, since that is the directory that needs to be written to create it.
 pub fn is_read_only<P: AsRef<Path> + std::fmt::Display>(path: &P) -> Result<bool> {
   debug!("Checking if {} is read-only", path.yellow());
-  let metadata = fs::metadata(path)?;
+  let existing = path
+    .as_ref()
+    .ancestors()
+    .find(|p| p.symlink_metadata().is_ok())
+    .ok_or(eyre!("unable to find an existing ancestor of {}", path.yellow()))?;
+  let metadata = fs::metadata(existing)?;
   let is_read_only = metadata.permissions().readonly();
   debug!("Is {} read-only: {}", path.yellow(), print_bool!(is_read_only, "readonly", "write allowed"));
   Ok(is_read_only)
 }
 
+/// Create a directory and its parents, retrying with sudo when the user is not allowed to.
+///
+/// On a multi-user nix installation the profile directories are owned by root.
+pub fn create_dir_all(path: &Path, askpass: Option<&Path>) -> Result<()> {
+  let err = match fs::create_dir_all(path) {
+    Ok(()) => return Ok(()),
+    Err(err) => err,
+  };
+  if !needs_sudo(&err, is_root_user()?) {
+    return Err(eyre!("unable to create {}", path.display()).with_error(|| err));
+  }
+  warn!("unable to create {}, retrying with sudo", path.display().yellow());
+  let status = sudo_create_dir_all_cmd(path, askpass).trace().join()?;
+  if status.success() {
+    Ok(())
+  } else {
+    bail!("Failed to run sudo mkdir");
+  }
+}
+
+fn needs_sudo(err: &std::io::Error, is_root: bool) -> bool {
+  err.kind() == std::io::ErrorKind::PermissionDenied && !is_root
+}
+
+fn sudo_create_dir_all_cmd(path: &Path, askpass: Option<&Path>) -> Exec {
+  sudo_cmd(askpass).arg("mkdir").arg("-p").arg(path)
+}
+
+/// The sudo command, asking for the password through the `askpass` program when given
+pub fn sudo_cmd(askpass: Option<&Path>) -> Exec {
+  match askpass {
+    Some(askpass) => Exec::cmd("sudo").env("SUDO_ASKPASS", askpass).arg("-A"),
+    None => Exec::cmd("sudo"),
+  }
+}
+
 pub fn sudo_nix_env_profile<Profile, ExtraProfileFlagsItems>(
-  profile: Profile, extra_profile_flags: &[ExtraProfileFlagsItems],
+  profile: Profile, extra_profile_flags: &[ExtraProfileFlagsItems], askpass: Option<&Path>,
 ) -> Result<()>
 where
   Profile: AsRef<OsStr>,
   ExtraProfileFlagsItems: AsRef<OsStr>,
 {
-  let status = Exec::cmd("sudo").arg("nix-env").arg("-p").arg(profile).args(extra_profile_flags).trace().join()?;
+  let status = sudo_cmd(askpass).arg("nix-env").arg("-p").arg(profile).args(extra_profile_flags).trace().join()?;
   if status.success() {
     Ok(())
   } else {
@@ -254,21 +858,212 @@ pub fn nix_env_profile(profile: &impl AsRef<OsStr>, extra_profile_flags: &[&impl
   }
 }
 
+/// Get the generation number marked as `(current)` in the profile
+pub fn current_generation(profile: &(impl AsRef<OsStr> + std::fmt::Display)) -> Result<u32> {
+  debug!("Getting current generation of {}", profile.yellow());
+  let stdout = nix_env_list_generations(profile)?;
+  parse_current_generation(&stdout)
+    .ok_or(eyre!("unable to find the current generation of {}", profile.yellow()).with_section(|| stdout))
+}
+
+fn nix_env_list_generations(profile: &(impl AsRef<OsStr> + std::fmt::Display)) -> Result<String> {
+  let output = Exec::cmd("nix-env").arg("-p").arg(profile).arg("--list-generations").trace().capture()?;
+  let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+  if output.exit_status.success() {
+    Ok(stdout)
+  } else {
+    Err(eyre!("Failed to run nix-env --list-generations").with_section(|| stdout.header("stdout: ")))
+  }
+}
+
+/// A generation of a profile
+#[derive(Debug, Eq, PartialEq, Clone, serde::Serialize)]
+pub struct Generation {
+  pub number: u32,
+  pub date: String,
+  pub current: bool,
+  /// The store path the `<profile>-<number>-link` symlink points to
+  pub store_path: Option<String>,
+  /// The note given with `--message` when switching to the generation
+  pub note: Option<String>,
+}
+
+/// List the generations of the profile along with the store path each of them points to
+pub fn list_generations_detailed(profile: &(impl AsRef<OsStr> + std::fmt::Display)) -> Result<Vec<Generation>> {
+  debug!("Listing generations of {}", profile.yellow());
+  let profile_path = Path::new(profile);
+  if profile_path.symlink_metadata().is_err() {
+    return Ok(vec![]);
+  }
+  let stdout = nix_env_list_generations(profile)?;
+  Ok(parse_generations(&stdout, &profile_path))
+}
+
+/// Parse the output of `nix-env --list-generations`, resolving the generation symlinks next to the profile
+pub fn parse_generations(output: &str, profile: &impl AsRef<Path>) -> Vec<Generation> {
+  output
+    .lines()
+    .filter_map(|line| {
+      let mut tokens = line.split_whitespace();
+      let number: u32 = tokens.next()?.parse().ok()?;
+      let date = [tokens.next()?, tokens.next()?].join(" ");
+      let current = tokens.next() == Some("(current)");
+      let link = format!("{}-{}-link", profile.as_ref().display(), number);
+      let store_path = fs::read_link(link).ok().map(|path| path.display().to_string());
+      Some(Generation { number, date, current, store_path, note: None })
+    })
+    .collect()
+}
+
+/// Resolve the store path of a generation from its `<profile>-<number>-link` symlink
+pub fn generation_store_path(profile: &impl AsRef<Path>, number: u32) -> Result<String> {
+  let link = format!("{}-{}-link", profile.as_ref().display(), number);
+  debug!("Resolving generation {} from {}", number.yellow(), link.yellow());
+  let store_path = fs::read_link(&link).map_err(|e| eyre!("generation {} does not exist", number).with_error(|| e))?;
+  Ok(store_path.display().to_string())
+}
+
+/// The generations of the profile to delete to only keep the newest `keep` ones and the current one
+pub fn prune_generations(
+  profile: &(impl AsRef<OsStr> + AsRef<Path> + std::fmt::Display), keep: usize,
+) -> Result<Vec<u32>> {
+  let generations = parse_generations(&nix_env_list_generations(profile)?, profile);
+  Ok(generations_to_delete(&generations, keep))
+}
+
+/// Compute the generations to delete to only keep the newest `keep` ones, never including the current generation
+pub fn generations_to_delete(generations: &[Generation], keep: usize) -> Vec<u32> {
+  let current = generations.iter().find(|generation| generation.current).map(|generation| generation.number);
+  let mut numbers: Vec<_> = generations.iter().map(|generation| generation.number).collect();
+  numbers.sort_unstable_by(|a, b| b.cmp(a));
+  let mut to_delete: Vec<_> = numbers.into_iter().skip(keep).filter(|number| Some(*number) != current).collect();
+  to_delete.sort_unstable();
+  to_delete
+}
+
+/// Find the generation a rollback switches to, the newest one older than the current generation
+pub fn rollback_target(generations: &[Generation]) -> Option<&Generation> {
+  let current = generations.iter().find(|generation| generation.current)?;
+  generations.iter().filter(|generation| generation.number < current.number).max_by_key(|generation| generation.number)
+}
+
+/// A named system profile
+#[derive(Debug, Eq, PartialEq, Clone, serde::Serialize)]
+pub struct SystemProfile {
+  pub name: String,
+  /// The current generation, `None` if it can't be determined
+  pub generation: Option<u32>,
+}
+
+/// List the named system profiles in `dir` along with their current generation
+pub fn list_system_profiles(dir: &Path) -> Result<Vec<SystemProfile>> {
+  debug!("Listing system profiles in {}", dir.display().yellow());
+  Ok(
+    system_profile_names(dir)?
+      .into_iter()
+      .map(|name| {
+        let generation = current_generation(&dir.join(&name).display().to_string()).ok();
+        SystemProfile { name, generation }
+      })
+      .collect(),
+  )
+}
+
+/// Get the names of the profiles in `dir`, skipping their `<name>-<number>-link` generation links
+fn system_profile_names(dir: &Path) -> Result<Vec<String>> {
+  if !dir.is_dir() {
+    return Ok(vec![]);
+  }
+  let generation_link = regex::Regex::new(r"-\d+-link$").unwrap();
+  let mut names = vec![];
+  for entry in fs::read_dir(dir)? {
+    let name = entry?.file_name().to_string_lossy().to_string();
+    if !generation_link.is_match(&name) {
+      names.push(name);
+    }
+  }
+  names.sort();
+  Ok(names)
+}
+
+/// Render the system profiles as a table
+pub fn render_system_profiles(profiles: &[SystemProfile]) -> String {
+  use std::fmt::Write;
+
+  let width = profiles.iter().map(|profile| profile.name.len()).max().unwrap_or_default().max("PROFILE".len());
+  let mut table = format!("{:<width$}  {}\n", "PROFILE", "GENERATION");
+  for profile in profiles {
+    let generation = profile.generation.map(|generation| generation.to_string()).unwrap_or("-".to_string());
+    let _ = writeln!(table, "{:<width$}  {}", profile.name, generation);
+  }
+  table
+}
+
+/// Render the generations as a table
+pub fn render_generations(generations: &[Generation]) -> String {
+  use std::fmt::Write;
+
+  let mut table = format!("{:>10}  {:<19}  {:<9}  {}\n", "GENERATION", "DATE", "CURRENT", "STORE PATH");
+  for generation in generations {
+    let current = if generation.current { "*" } else { "" };
+    let store_path = generation.store_path.as_deref().unwrap_or("-");
+    let _ = write!(table, "{:>10}  {:<19}  {:<9}  {}", generation.number, generation.date, current, store_path);
+    let _ = match &generation.note {
+      Some(note) => writeln!(table, "  {}", note),
+      None => writeln!(table),
+    };
+  }
+  table
+}
+
+/// Parse the output of `nix-env --list-generations` to find the current generation number
+pub fn parse_current_generation(output: &str) -> Option<u32> {
+  output
+    .lines()
+    .map(str::trim)
+    .find(|line| line.ends_with("(current)"))
+    .and_then(|line| line.split_whitespace().next())
+    .and_then(|generation| generation.parse().ok())
+}
+
+/// Read the system configuration the profile points to from its `systemConfig` file
+pub fn profile_system_config(profile: &(impl AsRef<Path> + std::fmt::Display)) -> Result<String> {
+  let file = profile.as_ref().join("systemConfig");
+  debug!("Reading the system configuration from {}", file.display().yellow());
+  let system_config =
+    fs::read_to_string(&file).map_err(|e| eyre!("unable to read {}", file.display()).with_error(|| e))?;
+  Ok(system_config.trim().to_string())
+}
+
 pub fn get_real_path(path: &(impl AsRef<Path> + std::fmt::Debug)) -> Result<String> {
-  let canonical_path = std::fs::canonicalize(&path)?;
+  let canonical_path = std::fs::canonicalize(path)?;
   canonical_path.to_str().ok_or(eyre!("unable to get the real path of {path:?}")).map(|e| e.to_string())
 }
 
+/// Check if `system_config` is the system that is currently active.
+///
+/// Both paths are resolved first, a path that can't be resolved is never considered active.
+pub fn is_current_system(current_system: &impl AsRef<Path>, system_config: &impl AsRef<Path>) -> bool {
+  match (fs::canonicalize(current_system), fs::canonicalize(system_config)) {
+    (Ok(current_system), Ok(system_config)) => current_system == system_config,
+    _ => false,
+  }
+}
+
 pub trait SetProfile {
-  fn sudo_nix_env_set_profile(profile: &impl AsRef<OsStr>, system_config: &impl AsRef<OsStr>) -> Result<()>;
+  fn sudo_nix_env_set_profile(
+    profile: &impl AsRef<OsStr>, system_config: &impl AsRef<OsStr>, askpass: Option<&Path>,
+  ) -> Result<()>;
 
   fn nix_env_set_profile(profile: &impl AsRef<OsStr>, system_config: &impl AsRef<OsStr>) -> Result<()>;
 }
 
 impl SetProfile for () {
-  fn sudo_nix_env_set_profile(profile: &impl AsRef<OsStr>, system_config: &impl AsRef<OsStr>) -> Result<()> {
+  fn sudo_nix_env_set_profile(
+    profile: &impl AsRef<OsStr>, system_config: &impl AsRef<OsStr>, askpass: Option<&Path>,
+  ) -> Result<()> {
     let status =
-      Exec::cmd("sudo").arg("nix-env").arg("-p").arg(profile).arg("--set").arg(system_config).trace().join()?;
+      sudo_cmd(askpass).arg("nix-env").arg("-p").arg(profile).arg("--set").arg(system_config).trace().join()?;
 
     if status.success() {
       Ok(())
@@ -287,12 +1082,76 @@ impl SetProfile for () {
   }
 }
 
-pub fn exec_activate_user<SystemConfig>(system_config: &SystemConfig) -> Result<()>
+/// Environment variable making the activation scripts only check the system
+const CHECK_ACTIVATION_ENV: &str = "checkActivation";
+
+/// Variables of the environment that change how the activation scripts behave
+const ACTIVATION_ENV: [&str; 2] = ["NIX_SSHOPTS", "NIX_PATH"];
+
+/// The variables set for the activation child only, the environment of darwin-rebuild is left untouched
+fn activation_vars(check: bool) -> Vec<(&'static str, &'static str)> {
+  if check {
+    vec![(CHECK_ACTIVATION_ENV, "1")]
+  } else {
+    vec![]
+  }
+}
+
+/// Describe the variables the activation runs with, only the relevant ones are listed to avoid leaking secrets.
+/// sudo resets the environment, so a root activation only sees the `keep_env` variables and `vars`
+fn activation_env(
+  vars: &[(&str, &str)], sudo: bool, keep_env: &[String], var: impl Fn(&str) -> Option<String>,
+) -> String {
+  let names: Vec<&str> = if sudo { keep_env.iter().map(String::as_str).collect() } else { ACTIVATION_ENV.to_vec() };
+  let inherited = names.into_iter().filter_map(|name| var(name).map(|value| format!("{}={}", name, value)));
+  inherited.chain(vars.iter().map(|(name, value)| format!("{}={}", name, value))).collect::<Vec<_>>().join(" ")
+}
+
+fn log_activation_env(vars: &[(&str, &str)], sudo: bool, keep_env: &[String]) {
+  debug!("Activation environment: {}", activation_env(vars, sudo, keep_env, |name| env::var(name).ok()).yellow());
+}
+
+/// The sudo flag keeping `keep_env` in the environment of the root command
+fn preserve_env_arg(keep_env: &[String]) -> Option<String> {
+  (!keep_env.is_empty()).then(|| format!("--preserve-env={}", keep_env.join(",")))
+}
+
+fn activate_cmd(
+  system_config: &impl std::fmt::Display, script: &str, sudo: bool, vars: &[(&str, &str)], keep_env: &[String],
+  askpass: Option<&Path>,
+) -> Exec {
+  let command = format!("{}/{}", system_config, script);
+  if !sudo {
+    return vars.iter().fold(Exec::cmd(command), |cmd, (name, value)| cmd.env(name, value));
+  }
+  let sudo = sudo_cmd(askpass).args(preserve_env_arg(keep_env).as_slice());
+  if vars.is_empty() {
+    return sudo.arg(command);
+  }
+  // sudo resets the environment, so the variables are set by env as root
+  let assignments: Vec<_> = vars.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+  sudo.arg("env").args(&assignments).arg(command)
+}
+
+/// The command running the user activation, with `check` the system is only checked through `checkActivation`
+pub fn activate_user_cmd(system_config: &impl std::fmt::Display, check: bool) -> Exec {
+  activate_cmd(system_config, "activate-user", false, &activation_vars(check), &[], None)
+}
+
+/// The command running the system activation, as root through sudo when `sudo` is set
+pub fn activate_system_cmd(
+  system_config: &impl std::fmt::Display, sudo: bool, keep_env: &[String], askpass: Option<&Path>,
+) -> Exec {
+  activate_cmd(system_config, "activate", sudo, &[], keep_env, askpass)
+}
+
+/// Run the user activation, with `check` the system is only checked through `checkActivation`
+pub fn exec_activate_user<SystemConfig>(system_config: &SystemConfig, check: bool) -> Result<()>
 where
   SystemConfig: std::fmt::Display,
 {
-  let command = format!("{}/activate-user", system_config);
-  let status = Exec::cmd(command).trace().join()?;
+  log_activation_env(&activation_vars(check), false, &[]);
+  let status = activate_user_cmd(system_config, check).trace().join()?;
   if status.success() {
     Ok(())
   } else {
@@ -300,12 +1159,15 @@ where
   }
 }
 
-pub fn sudo_exec_activate<SystemConfig>(system_config: &SystemConfig) -> Result<()>
+/// Run the system activation as root, keeping the `keep_env` variables of the environment
+pub fn sudo_exec_activate<SystemConfig>(
+  system_config: &SystemConfig, keep_env: &[String], askpass: Option<&Path>,
+) -> Result<()>
 where
   SystemConfig: std::fmt::Display,
 {
-  let command = format!("{}/activate", system_config);
-  let status = Exec::cmd("sudo").arg(command).trace().join()?;
+  log_activation_env(&[], true, keep_env);
+  let status = activate_system_cmd(system_config, true, keep_env, askpass).trace().join()?;
 
   if status.success() {
     Ok(())
@@ -318,9 +1180,9 @@ pub fn exec_activate<SystemConfig>(system_config: &SystemConfig) -> Result<()>
 where
   SystemConfig: std::fmt::Display,
 {
-  let command = format!("{}/activate", system_config);
-  info!("Running {}", command.yellow());
-  let status = Exec::cmd(command).trace().join()?;
+  info!("Running {}", format!("{}/activate", system_config).yellow());
+  log_activation_env(&[], false, &[]);
+  let status = activate_system_cmd(system_config, false, &[], None).trace().join()?;
 
   if status.success() {
     Ok(())
@@ -335,10 +1197,766 @@ where
 {
   let file = format!("{}/darwin-changes", system_config);
   debug!("Printing changelog for {}", file.yellow());
-  let changelog = fs::read_to_string(file)?;
+  let changelog = match fs::read_to_string(&file) {
+    Ok(changelog) => changelog,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+      warn!("no changelog available for this generation");
+      return Ok(());
+    },
+    Err(e) => return Err(eyre!("unable to read the changelog {}", file).with_error(|| e)),
+  };
   let lines: Vec<&str> = changelog.lines().take(32).collect();
   for line in lines {
     println!("{}", line);
   }
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_eq;
+  use rstest::rstest;
+
+  use super::*;
+
+  const LIST_GENERATIONS: &str = r#"   1   2024-06-01 10:12:45
+   2   2024-06-12 18:03:11
+   3   2024-07-02 09:41:27   (current)
+"#;
+
+  const NVD_DIFF: &str = r#"<<< /nix/var/nix/profiles/system
+>>> /tmp/nix-darwin-abc/result
+Version changes:
+[U.]  #1  bat                 0.24.0 -> 0.25.0
+[U*]  #2  git                 2.44.0 -> 2.45.1
+[D.]  #3  jq                  1.7.1 -> 1.7
+Added packages:
+[A.]  #1  ripgrep             14.1.0
+[A*]  #2  fd                  10.1.0
+Removed packages:
+[R.]  #1  exa                 0.10.1
+Closure size: 1234 -> 1240 (12 paths added, 6 paths removed, delta +6, disk usage +1.2MiB).
+"#;
+
+  const NOM_OUTPUT: &str = r#"@nix {"action":"start","id":1,"level":3,"text":"building '/nix/store/0c9fhmsnz1ls6dyg8a6sdyl2ww6c5hh2-darwin-system.drv'","type":105}
+{"action":"msg","level":3,"msg":"copying path '/nix/store/9fa0r9wyz8wv5z9g8f0pswgm2dpbzzsd-source' from 'https://cache.nixos.org'"}
+{"action":"msg","level":0,"msg":"error: builder for '/nix/store/4gs0xnazdw8cb3zlzixpbrdjlj2z4dyg-hello-2.12.1.drv' failed with exit code 1"}
+{"action":"msg","level":0,"msg":"error: 1 dependencies of derivation '/nix/store/0c9fhmsnz1ls6dyg8a6sdyl2ww6c5hh2-darwin-system.drv' failed to build"}
+{"action":"stop","id":1}
+"#;
+
+  #[test_log::test]
+  fn test_parse_build_failures() {
+    let failures = parse_build_failures(NOM_OUTPUT);
+    assert_eq!(failures, [
+      "/nix/store/4gs0xnazdw8cb3zlzixpbrdjlj2z4dyg-hello-2.12.1.drv",
+      "/nix/store/0c9fhmsnz1ls6dyg8a6sdyl2ww6c5hh2-darwin-system.drv"
+    ]);
+  }
+
+  fn capture_data(stdout: &str, stderr: &str, exit_code: u32) -> subprocess::CaptureData {
+    subprocess::CaptureData {
+      stdout: stdout.as_bytes().to_vec(),
+      stderr: stderr.as_bytes().to_vec(),
+      exit_status: subprocess::ExitStatus::Exited(exit_code),
+    }
+  }
+
+  #[test_log::test]
+  fn test_parse_flake_metadata_output() -> Result<()> {
+    let output = capture_data(r#"{"url":"git+file:///etc/nix-darwin"}"#, "", 0);
+    let metadata = parse_flake_metadata_output(&output)?;
+    assert_eq!(metadata["url"], "git+file:///etc/nix-darwin");
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_parse_flake_metadata_output_with_failure_shows_stderr() {
+    let stderr = "error: unable to download 'https://github.com/private/repo': HTTP error 401";
+    let output = capture_data("", stderr, 1);
+    let err = parse_flake_metadata_output(&output).unwrap_err();
+    assert!(format!("{:?}", err).contains(stderr), "{:?}", err);
+  }
+
+  #[test_log::test]
+  fn test_parse_flake_metadata_output_checks_exit_status() {
+    let output = capture_data("", "error: getting status of '/etc/nix-darwin': No such file or directory", 1);
+    let err = parse_flake_metadata_output(&output).unwrap_err();
+    assert!(err.to_string().starts_with("nix flake metadata failed"), "{:?}", err);
+    assert!(err.chain().all(|cause| !cause.is::<serde_json::Error>()), "{:?}", err);
+  }
+
+  #[test_log::test]
+  fn test_parse_flake_metadata_output_with_invalid_json_shows_stderr() {
+    let output = capture_data("not json", "warning: Git tree '/etc/nix-darwin' is dirty", 0);
+    let report = format!("{:?}", parse_flake_metadata_output(&output).unwrap_err());
+    assert!(report.contains("unable to parse flake metadata"), "{}", report);
+    assert!(report.contains("is dirty"), "{}", report);
+  }
+
+  #[rstest]
+  #[case::permission_denied(std::io::ErrorKind::PermissionDenied, false, true)]
+  #[case::permission_denied_as_root(std::io::ErrorKind::PermissionDenied, true, false)]
+  #[case::other_error(std::io::ErrorKind::NotFound, false, false)]
+  fn test_needs_sudo(#[case] kind: std::io::ErrorKind, #[case] is_root: bool, #[case] expected: bool) {
+    assert_eq!(needs_sudo(&std::io::Error::from(kind), is_root), expected);
+  }
+
+  #[rstest]
+  #[case::without_askpass(None, "sudo mkdir -p /nix/var/nix/profiles/system-profiles")]
+  #[case::askpass(
+    Some("/usr/local/bin/askpass"),
+    "SUDO_ASKPASS=/usr/local/bin/askpass sudo -A mkdir -p /nix/var/nix/profiles/system-profiles"
+  )]
+  fn test_sudo_create_dir_all_cmd(#[case] askpass: Option<&str>, #[case] expected: &str) {
+    let cmd = sudo_create_dir_all_cmd(Path::new("/nix/var/nix/profiles/system-profiles"), askpass.map(Path::new));
+    assert_eq!(cmd.to_cmdline_lossy(), expected);
+  }
+
+  #[rstest]
+  #[case::single("/nix/store/abc-darwin-system\t 2147483648\n", 2147483648)]
+  #[case::aligned("/nix/store/abc-darwin-system   123\n\n", 123)]
+  #[test_log::test]
+  fn test_parse_closure_size(#[case] output: &str, #[case] expected: u64) -> Result<()> {
+    assert_eq!(parse_closure_size(output)?, expected);
+    Ok(())
+  }
+
+  #[rstest]
+  #[case::empty("")]
+  #[case::missing_size("/nix/store/abc-darwin-system\n")]
+  #[test_log::test]
+  fn test_parse_closure_size_rejects_invalid_output(#[case] output: &str) {
+    assert!(parse_closure_size(output).is_err());
+  }
+
+  #[rstest]
+  #[case::bytes(512, "512 B")]
+  #[case::kibibytes(1536, "1.5 KiB")]
+  #[case::gibibytes(2147483648, "2.0 GiB")]
+  #[test_log::test]
+  fn test_format_size(#[case] bytes: u64, #[case] expected: &str) {
+    assert_eq!(format_size(bytes), expected);
+  }
+
+  #[test_log::test]
+  fn test_closure_size_cmd() {
+    assert_eq!(
+      closure_size_cmd("/nix/store/abc-darwin-system", &[] as &[&str]).to_cmdline_lossy(),
+      "nix path-info --closure-size /nix/store/abc-darwin-system"
+    );
+  }
+
+  #[test_log::test]
+  fn test_store_diff_closures_cmd() {
+    let flags = ["--extra-experimental-features", "nix-command flakes"];
+    assert_eq!(
+      store_diff_closures_cmd("/run/current-system", "/nix/store/abc-darwin-system", &flags).to_cmdline_lossy(),
+      "nix --extra-experimental-features 'nix-command flakes' store diff-closures /run/current-system /nix/store/abc-darwin-system"
+    );
+  }
+
+  #[rstest]
+  #[case::enabled_failure(true, false, 1)]
+  #[case::disabled_failure(false, false, 0)]
+  #[case::enabled_success(true, true, 0)]
+  #[test_log::test]
+  fn test_with_verbose_retry(#[case] enabled: bool, #[case] succeeds: bool, #[case] expected_retries: usize) {
+    let mut retries = 0;
+    let result = with_verbose_retry(
+      enabled,
+      || if succeeds { Ok("result") } else { Err(eyre!("Failed to build the system configuration")) },
+      || {
+        retries += 1;
+        Err(eyre!("verbose build failed"))
+      },
+    );
+    assert_eq!(retries, expected_retries);
+    match result {
+      Ok(value) => assert!(succeeds, "{value}"),
+      Err(err) => assert_eq!(err.to_string(), "Failed to build the system configuration"),
+    }
+  }
+
+  #[test_log::test]
+  fn test_nix_flake_build_verbose_cmd() {
+    assert_eq!(
+      nix_flake_build_verbose_cmd(&"/etc/nix-darwin", &"host", &[] as &[&str], Some("/tmp/result"), &[] as &[&str])
+        .to_cmdline_lossy(),
+      "nix build -L --show-trace --out-link /tmp/result -- '/etc/nix-darwin#host.system'"
+    );
+  }
+
+  #[test_log::test]
+  fn test_tee_lines() -> Result<()> {
+    let input = "building /nix/store/x-darwin-system.drv\nfailed\n";
+    let mut out = vec![];
+    assert_eq!(tee_lines(input.as_bytes(), &mut out)?, input);
+    assert_eq!(String::from_utf8(out)?, input);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_flake_build_outcome() -> Result<()> {
+    let stdout = br#"[{"drvPath":"/nix/store/x-darwin-system.drv","outputs":{"out":"/nix/store/x-darwin-system"}}]"#;
+    assert_eq!(flake_build_outcome(stdout)?, BuildOutcome {
+      out_path: "/nix/store/x-darwin-system".to_string(),
+      used_nom: false,
+      diffed: false
+    });
+    let err = flake_build_outcome(br#"[{"outputs":{}}]"#).unwrap_err();
+    assert_eq!(err.to_string(), "unable to get output");
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_no_link_skips_out_link() {
+    let cmd = nix_flake_build_cmd(&"/etc/nix-darwin", &"host", &[] as &[&str], None, &[] as &[&str]).to_cmdline_lossy();
+    assert_eq!(cmd, "nix build --json --log-format internal-json --no-link -- '/etc/nix-darwin#host.system'");
+    assert!(!cmd.contains("--out-link"), "{cmd}");
+    assert_eq!(
+      nix_build_cmd("/etc/darwin", "system", None, &[] as &[&str]).to_cmdline_lossy(),
+      "nix-build /etc/darwin --no-out-link -A system"
+    );
+  }
+
+  #[test_log::test]
+  fn test_require_nix_finds_nix_in_path() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir()?;
+    let nix = dir.path().join("nix");
+    fs::write(&nix, "#!/bin/sh\n")?;
+    fs::set_permissions(&nix, fs::Permissions::from_mode(0o755))?;
+    let path = env::join_paths(["/nonexistent".as_ref(), dir.path()])?;
+    require_nix(Some(&path))?;
+    Ok(())
+  }
+
+  #[rstest]
+  #[case::empty_dir(true)]
+  #[case::unset(false)]
+  #[test_log::test]
+  fn test_require_nix_without_nix(#[case] set: bool) -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    // a file that isn't executable is not enough
+    fs::write(dir.path().join("nix"), "")?;
+    let path = dir.path().as_os_str();
+    let err = require_nix(set.then_some(path)).unwrap_err();
+    assert_eq!(err.to_string(), "nix is not installed or not in $PATH");
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_why_depends_cmd() {
+    let flags = ["--extra-experimental-features", "nix-command flakes"];
+    assert_eq!(
+      why_depends_cmd("/nix/store/abc-darwin-system", "nixpkgs#hello", &flags).to_cmdline_lossy(),
+      "nix --extra-experimental-features 'nix-command flakes' why-depends /nix/store/abc-darwin-system 'nixpkgs#hello'"
+    );
+  }
+
+  #[test_log::test]
+  fn test_store_flags_reach_every_nix_command() {
+    let flags = ["--store", "daemon", "--eval-store", "auto"];
+    assert_eq!(
+      flake_metadata_cmd("/etc/nix-darwin", "metadata", &[] as &[&str], &flags).to_cmdline_lossy(),
+      "nix flake metadata --json --store daemon --eval-store auto -- /etc/nix-darwin"
+    );
+    assert_eq!(
+      nix_flake_build_cmd(&"/etc/nix-darwin", &"host", &[] as &[&str], Some("/tmp/result"), &flags).to_cmdline_lossy(),
+      "nix build --json --log-format internal-json --out-link /tmp/result --store daemon --eval-store auto -- '/etc/nix-darwin#host.system'"
+    );
+    assert_eq!(
+      nix_build_cmd("/etc/darwin", "system", Some("/tmp/result"), &flags).to_cmdline_lossy(),
+      "nix-build /etc/darwin --store daemon --eval-store auto --out-link /tmp/result -A system"
+    );
+  }
+
+  #[rstest]
+  #[case::user(false, "/nix/store/x-darwin-system/activate")]
+  #[case::sudo(true, "sudo /nix/store/x-darwin-system/activate")]
+  fn test_activate_system_cmd(#[case] sudo: bool, #[case] expected: &str) {
+    let cmd = activate_system_cmd(&"/nix/store/x-darwin-system", sudo, &[], None);
+    assert_eq!(cmd.to_cmdline_lossy(), expected);
+  }
+
+  #[rstest]
+  #[case::activate(false, "/nix/store/x-darwin-system/activate-user")]
+  #[case::check(true, "checkActivation=1 /nix/store/x-darwin-system/activate-user")]
+  fn test_activate_user_cmd(#[case] check: bool, #[case] expected: &str) {
+    assert_eq!(activate_user_cmd(&"/nix/store/x-darwin-system", check).to_cmdline_lossy(), expected);
+  }
+
+  #[rstest]
+  #[case::user(false, "/nix/store/x-darwin-system/activate")]
+  #[case::sudo(
+    true,
+    "SUDO_ASKPASS=/usr/local/bin/askpass sudo -A env 'checkActivation=1' /nix/store/x-darwin-system/activate"
+  )]
+  fn test_activate_cmd_with_askpass(#[case] sudo: bool, #[case] expected: &str) {
+    let askpass = Path::new("/usr/local/bin/askpass");
+    let vars = if sudo { activation_vars(true) } else { vec![] };
+    let cmd = activate_cmd(&"/nix/store/x-darwin-system", "activate", sudo, &vars, &[], Some(askpass));
+    assert_eq!(cmd.to_cmdline_lossy(), expected);
+  }
+
+  #[rstest]
+  #[case::without_askpass(None, "sudo nix-env")]
+  #[case::askpass(Some("/usr/local/bin/askpass"), "SUDO_ASKPASS=/usr/local/bin/askpass sudo -A nix-env")]
+  fn test_sudo_cmd(#[case] askpass: Option<&str>, #[case] expected: &str) {
+    assert_eq!(sudo_cmd(askpass.map(Path::new)).arg("nix-env").to_cmdline_lossy(), expected);
+    assert!(env::var("SUDO_ASKPASS").is_err());
+  }
+
+  #[rstest]
+  #[case::empty(&[], None)]
+  #[case::single(&["HOMEBREW_NO_AUTO_UPDATE"], Some("--preserve-env=HOMEBREW_NO_AUTO_UPDATE"))]
+  #[case::list(&["HOMEBREW_NO_AUTO_UPDATE", "HTTPS_PROXY"], Some("--preserve-env=HOMEBREW_NO_AUTO_UPDATE,HTTPS_PROXY"))]
+  fn test_preserve_env_arg(#[case] keep_env: &[&str], #[case] expected: Option<&str>) {
+    let keep_env: Vec<_> = keep_env.iter().map(|name| name.to_string()).collect();
+    assert_eq!(preserve_env_arg(&keep_env).as_deref(), expected);
+  }
+
+  #[rstest]
+  #[case::user(false, false, "/nix/store/x-darwin-system/activate")]
+  #[case::sudo(
+    true,
+    false,
+    "sudo '--preserve-env=HOMEBREW_NO_AUTO_UPDATE,HTTPS_PROXY' /nix/store/x-darwin-system/activate"
+  )]
+  #[case::check_sudo(
+    true,
+    true,
+    "sudo '--preserve-env=HOMEBREW_NO_AUTO_UPDATE,HTTPS_PROXY' env 'checkActivation=1' /nix/store/x-darwin-system/activate"
+  )]
+  fn test_activate_cmd_keeps_env_vars(#[case] sudo: bool, #[case] check: bool, #[case] expected: &str) {
+    let keep_env = ["HOMEBREW_NO_AUTO_UPDATE".to_string(), "HTTPS_PROXY".to_string()];
+    let cmd = activate_cmd(&"/nix/store/x-darwin-system", "activate", sudo, &activation_vars(check), &keep_env, None);
+    assert_eq!(cmd.to_cmdline_lossy(), expected);
+  }
+
+  #[rstest]
+  #[case::user(false, "checkActivation=1 /nix/store/x-darwin-system/activate-user")]
+  #[case::sudo(true, "sudo env 'checkActivation=1' /nix/store/x-darwin-system/activate-user")]
+  fn test_check_activation_is_set_on_the_child_only(#[case] sudo: bool, #[case] expected: &str) {
+    let cmd = activate_cmd(&"/nix/store/x-darwin-system", "activate-user", sudo, &activation_vars(true), &[], None);
+    assert_eq!(cmd.to_cmdline_lossy(), expected);
+    assert!(env::var(CHECK_ACTIVATION_ENV).is_err());
+  }
+
+  #[rstest]
+  #[case::unset(&[], false, false, &[], "")]
+  #[case::check(&[], true, false, &[], "checkActivation=1")]
+  #[case::ssh_opts(&[("NIX_SSHOPTS", "-p 2222"), ("AWS_SECRET_ACCESS_KEY", "secret")], false, false, &[], "NIX_SSHOPTS=-p 2222")]
+  #[case::nix_path(&[("NIX_PATH", "nixpkgs=/nix")], true, false, &[], "NIX_PATH=nixpkgs=/nix checkActivation=1")]
+  #[case::sudo_resets(&[("NIX_PATH", "nixpkgs=/nix")], false, true, &[], "")]
+  #[case::sudo_keeps(
+    &[("NIX_PATH", "nixpkgs=/nix"), ("HTTPS_PROXY", "http://proxy")],
+    true,
+    true,
+    &["HTTPS_PROXY", "HOMEBREW_NO_AUTO_UPDATE"],
+    "HTTPS_PROXY=http://proxy checkActivation=1"
+  )]
+  fn test_activation_env(
+    #[case] env: &[(&str, &str)], #[case] check: bool, #[case] sudo: bool, #[case] keep_env: &[&str],
+    #[case] expected: &str,
+  ) {
+    let var = |name: &str| env.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string());
+    let keep_env: Vec<_> = keep_env.iter().map(|name| name.to_string()).collect();
+    assert_eq!(activation_env(&activation_vars(check), sudo, &keep_env, var), expected);
+  }
+
+  #[rstest]
+  #[case::fqdn("host.domain.com", "host")]
+  #[case::local("host.local", "host")]
+  #[case::short("host", "host")]
+  fn test_short_hostname(#[case] hostname: &str, #[case] expected: &str) {
+    assert_eq!(short_hostname(hostname), expected);
+  }
+
+  #[test_log::test]
+  fn test_trace_single_token_command() {
+    let cmd = Exec::cmd("activate-user").trace();
+    assert_eq!(cmd.to_cmdline_lossy(), "activate-user");
+  }
+
+  #[test_log::test]
+  fn test_flake_lock_update_input_cmd() {
+    let cmd = flake_lock_update_input_cmd("/etc/nix-darwin", "nixpkgs", &[] as &[&str]);
+    assert_eq!(cmd.to_cmdline_lossy(), "nix flake lock --update-input nixpkgs -- /etc/nix-darwin");
+  }
+
+  #[test_log::test]
+  fn test_git_commit_lockfile_cmds() {
+    let [add, commit] = git_commit_lockfile_cmds("/etc/nix-darwin", "Update flake input nixpkgs");
+    assert_eq!(add.to_cmdline_lossy(), "git -C /etc/nix-darwin add flake.lock");
+    assert_eq!(
+      commit.to_cmdline_lossy(),
+      "git -C /etc/nix-darwin commit -m 'Update flake input nixpkgs' -- flake.lock"
+    );
+  }
+
+  #[rstest]
+  #[case::path("path:/etc/nix-darwin?lastModified=1&narHash=sha256-abc", Some("/etc/nix-darwin"))]
+  #[case::git_file("git+file:///etc/nix-darwin?submodules=1", Some("/etc/nix-darwin"))]
+  #[case::absolute("/etc/nix-darwin#host", Some("/etc/nix-darwin"))]
+  #[case::github("github:owner/repo", None)]
+  #[case::git_https("git+https://example.com/repo", None)]
+  fn test_local_flake_dir(#[case] flake: &str, #[case] expected: Option<&str>) {
+    assert_eq!(local_flake_dir(flake), expected);
+  }
+
+  #[rstest]
+  #[case::code("code", &["code", "--wait"])]
+  #[case::code_path("/usr/local/bin/code", &["/usr/local/bin/code", "--wait"])]
+  #[case::code_with_wait("code --wait", &["code", "--wait"])]
+  #[case::subl("subl", &["subl", "-w"])]
+  #[case::subl_with_wait("subl --wait", &["subl", "--wait"])]
+  #[case::atom("atom", &["atom", "--wait"])]
+  #[case::vim("vim", &["vim"])]
+  #[case::nvim_with_args("nvim -u NONE", &["nvim", "-u", "NONE"])]
+  fn test_editor_command(#[case] editor: &str, #[case] expected: &[&str]) {
+    assert_eq!(editor_command(editor), expected);
+  }
+
+  #[rstest]
+  #[case::without_system("darwinConfigurations.host")]
+  #[case::with_system("darwinConfigurations.host.system")]
+  fn test_system_attr(#[case] flake_attr: &str) {
+    assert_eq!(system_attr(&flake_attr), "darwinConfigurations.host.system");
+  }
+
+  #[test_log::test]
+  fn test_parse_build_errors() {
+    assert_eq!(parse_build_errors(NOM_OUTPUT), [
+      "error: builder for '/nix/store/4gs0xnazdw8cb3zlzixpbrdjlj2z4dyg-hello-2.12.1.drv' failed with exit code 1",
+      "error: 1 dependencies of derivation '/nix/store/0c9fhmsnz1ls6dyg8a6sdyl2ww6c5hh2-darwin-system.drv' failed to \
+       build"
+    ]);
+  }
+
+  #[test_log::test]
+  fn test_retry_succeeds_after_transient_failures() {
+    let mut calls = 0;
+    let result = retry_with_backoff(3, std::time::Duration::ZERO, || {
+      calls += 1;
+      if calls <= 2 {
+        Err(eyre!("unable to download 'https://cache.nixos.org/nix-cache-info': HTTP error 503"))
+      } else {
+        Ok(calls)
+      }
+    });
+    assert_eq!(result.unwrap(), 3);
+    assert_eq!(calls, 3);
+  }
+
+  #[test_log::test]
+  fn test_retry_gives_up_after_retries() {
+    let mut calls = 0;
+    let result: Result<()> = retry_with_backoff(2, std::time::Duration::ZERO, || {
+      calls += 1;
+      Err(eyre!("error: unable to download: Could not resolve host: cache.nixos.org"))
+    });
+    assert!(result.is_err());
+    assert_eq!(calls, 3);
+  }
+
+  #[test_log::test]
+  fn test_retry_does_not_retry_evaluation_errors() {
+    let mut calls = 0;
+    let result: Result<()> = retry_with_backoff(3, std::time::Duration::ZERO, || {
+      calls += 1;
+      Err(eyre!("error: attribute 'darwinConfigurations' missing"))
+    });
+    assert!(result.is_err());
+    assert_eq!(calls, 1);
+  }
+
+  #[test_log::test]
+  fn test_parse_build_failures_from_text() {
+    let output = "\x1b[31merror:\x1b[0m builder for '/nix/store/4gs0xnazdw8cb3zlzixpbrdjlj2z4dyg-hello-2.12.1.drv' \
+                  failed with exit code 1\n";
+    assert_eq!(parse_build_failures(output), ["/nix/store/4gs0xnazdw8cb3zlzixpbrdjlj2z4dyg-hello-2.12.1.drv"]);
+  }
+
+  #[test_log::test]
+  fn test_parse_build_failures_without_failure() {
+    assert!(parse_build_failures("building '/nix/store/0c9fhmsnz1ls6dyg8a6sdyl2ww6c5hh2-darwin-system.drv'").is_empty());
+  }
+
+  #[test_log::test]
+  fn test_parse_nvd_diff() {
+    let summary = parse_nvd_diff(NVD_DIFF);
+    assert_eq!(summary, DiffSummary { upgraded: 2, downgraded: 1, added: 2, removed: 1 });
+    assert_eq!(summary.to_string(), "2 upgraded, 2 added, 1 removed, 1 downgraded");
+  }
+
+  #[test_log::test]
+  fn test_parse_nvd_diff_with_colors() {
+    let summary = parse_nvd_diff("\x1b[1m[\x1b[0m\x1b[32mA\x1b[0m.]  #1  ripgrep  14.1.0\n");
+    assert_eq!(summary, DiffSummary { added: 1, ..Default::default() });
+  }
+
+  #[test_log::test]
+  fn test_parse_nvd_diff_without_changes() {
+    let summary = parse_nvd_diff("<<< /a\n>>> /b\nNo version or selection state changes.\n");
+    assert_eq!(summary, DiffSummary::default());
+    assert_eq!(summary.to_string(), "0 upgraded, 0 added, 0 removed");
+  }
+
+  #[test_log::test]
+  fn test_discover_flake_in_fixture_directory() -> Result<()> {
+    let result = discover_flake(&"assets")?;
+    assert_eq!(result, Some(format!("path:{}", get_real_path(&"assets")?)));
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_discover_flake_walks_up_to_git_root() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    fs::create_dir(dir.path().join(".git"))?;
+    fs::write(dir.path().join("flake.nix"), "{}")?;
+    let nested = dir.path().join("hosts").join("darwin");
+    fs::create_dir_all(&nested)?;
+    let result = discover_flake(&nested)?;
+    assert_eq!(result, Some(format!("path:{}", get_real_path(&dir.path())?)));
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_discover_flake_stops_at_git_root() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    fs::write(dir.path().join("flake.nix"), "{}")?;
+    let repository = dir.path().join("repository");
+    fs::create_dir_all(repository.join(".git"))?;
+    assert_eq!(discover_flake(&repository)?, None);
+    Ok(())
+  }
+
+  #[rstest]
+  #[case::reference("./assets#darwin-rebuild-rs\n", Some("./assets#darwin-rebuild-rs"))]
+  #[case::only_first_line("  github:owner/repo  \nignored\n", Some("github:owner/repo"))]
+  #[case::empty("\n", None)]
+  fn test_read_flake_file(#[case] content: &str, #[case] expected: Option<&str>) -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    fs::write(dir.path().join(".flake"), content)?;
+    assert_eq!(read_flake_file(&dir.path())?.as_deref(), expected);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_read_flake_file_without_file() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    assert_eq!(read_flake_file(&dir.path())?, None);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_parse_current_generation() {
+    assert_eq!(parse_current_generation(LIST_GENERATIONS), Some(3));
+  }
+
+  #[test_log::test]
+  fn test_generation_store_path_with_fixture_links() -> Result<()> {
+    use std::os::unix::fs::symlink;
+
+    let dir = tempfile::tempdir()?;
+    let profile = dir.path().join("system");
+    symlink("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-darwin-system-1", dir.path().join("system-1-link"))?;
+    symlink("/nix/store/cccccccccccccccccccccccccccccccc-darwin-system-3", dir.path().join("system-3-link"))?;
+
+    assert_eq!(generation_store_path(&profile, 1)?, "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-darwin-system-1");
+    assert_eq!(generation_store_path(&profile, 3)?, "/nix/store/cccccccccccccccccccccccccccccccc-darwin-system-3");
+    let err = generation_store_path(&profile, 2).unwrap_err();
+    assert!(err.to_string().contains("generation 2 does not exist"), "{:?}", err);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_parse_generations_with_fixture_links() -> Result<()> {
+    use std::os::unix::fs::symlink;
+
+    let dir = tempfile::tempdir()?;
+    let profile = dir.path().join("system");
+    symlink("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-darwin-system-1", dir.path().join("system-1-link"))?;
+    symlink("/nix/store/cccccccccccccccccccccccccccccccc-darwin-system-3", dir.path().join("system-3-link"))?;
+
+    let generations = parse_generations(LIST_GENERATIONS, &profile);
+    assert_eq!(generations, [
+      Generation {
+        number: 1,
+        date: "2024-06-01 10:12:45".to_string(),
+        current: false,
+        store_path: Some("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-darwin-system-1".to_string()),
+        note: None,
+      },
+      Generation { number: 2, date: "2024-06-12 18:03:11".to_string(), current: false, store_path: None, note: None },
+      Generation {
+        number: 3,
+        date: "2024-07-02 09:41:27".to_string(),
+        current: true,
+        store_path: Some("/nix/store/cccccccccccccccccccccccccccccccc-darwin-system-3".to_string()),
+        note: None,
+      },
+    ]);
+    Ok(())
+  }
+
+  fn fixture_generations(current: u32) -> Vec<Generation> {
+    (1..=5)
+      .map(|number| {
+        Generation {
+          number,
+          date: "2024-06-01 10:12:45".to_string(),
+          current: number == current,
+          store_path: None,
+          note: None,
+        }
+      })
+      .collect()
+  }
+
+  #[test_log::test]
+  fn test_generations_to_delete_keeps_newest() {
+    assert_eq!(generations_to_delete(&fixture_generations(5), 2), [1, 2, 3]);
+  }
+
+  #[test_log::test]
+  fn test_generations_to_delete_never_deletes_current() {
+    assert_eq!(generations_to_delete(&fixture_generations(2), 2), [1, 3]);
+    assert_eq!(generations_to_delete(&fixture_generations(5), 0), [1, 2, 3, 4]);
+  }
+
+  #[test_log::test]
+  fn test_generations_to_delete_with_fewer_generations() {
+    assert!(generations_to_delete(&fixture_generations(5), 10).is_empty());
+  }
+
+  #[rstest]
+  #[case::previous(&[1, 2, 3], 3, Some(2))]
+  #[case::with_gaps(&[1, 4, 7], 7, Some(4))]
+  #[case::current_not_newest(&[1, 2, 3], 2, Some(1))]
+  #[case::first_generation(&[1, 2], 1, None)]
+  fn test_rollback_target(#[case] numbers: &[u32], #[case] current: u32, #[case] expected: Option<u32>) {
+    let generations: Vec<_> = numbers
+      .iter()
+      .map(|&number| {
+        Generation {
+          number,
+          date: "2024-06-01 10:12:45".to_string(),
+          current: number == current,
+          store_path: None,
+          note: None,
+        }
+      })
+      .collect();
+    assert_eq!(rollback_target(&generations).map(|generation| generation.number), expected);
+  }
+
+  #[test_log::test]
+  fn test_system_profile_names_with_fixture_directory() -> Result<()> {
+    use std::os::unix::fs::symlink;
+
+    let dir = tempfile::tempdir()?;
+    for (link, target) in [
+      ("work", "work-2-link"),
+      ("work-1-link", "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-darwin-system-1"),
+      ("work-2-link", "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-darwin-system-2"),
+      ("home", "home-1-link"),
+      ("home-1-link", "/nix/store/cccccccccccccccccccccccccccccccc-darwin-system-1"),
+    ] {
+      symlink(target, dir.path().join(link))?;
+    }
+    assert_eq!(system_profile_names(dir.path())?, ["home", "work"]);
+    assert!(system_profile_names(&dir.path().join("missing"))?.is_empty());
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_render_system_profiles() {
+    let profiles = [SystemProfile { name: "home".to_string(), generation: Some(1) }, SystemProfile {
+      name: "work-laptop".to_string(),
+      generation: None,
+    }];
+    assert_eq!(render_system_profiles(&profiles), "PROFILE      GENERATION\nhome         1\nwork-laptop  -\n");
+  }
+
+  #[test_log::test]
+  fn test_render_generations() {
+    let generations = [
+      Generation { number: 1, date: "2024-06-01 10:12:45".to_string(), current: false, store_path: None, note: None },
+      Generation {
+        number: 2,
+        date: "2024-06-12 18:03:11".to_string(),
+        current: true,
+        store_path: Some("/nix/store/x-darwin-system".to_string()),
+        note: Some("enable yabai".to_string()),
+      },
+    ];
+    let table = render_generations(&generations);
+    let lines: Vec<_> = table.lines().collect();
+    assert_eq!(lines, [
+      "GENERATION  DATE                 CURRENT    STORE PATH",
+      "         1  2024-06-01 10:12:45             -",
+      "         2  2024-06-12 18:03:11  *          /nix/store/x-darwin-system  enable yabai",
+    ]);
+  }
+
+  #[test_log::test]
+  fn test_parse_current_generation_without_current() {
+    assert_eq!(parse_current_generation("   1   2024-06-01 10:12:45   \n"), None);
+    assert_eq!(parse_current_generation(""), None);
+  }
+
+  #[test_log::test]
+  fn test_is_read_only_with_missing_path_checks_parent() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let profile = dir.path().join("system-profiles").join("missing");
+    let profile = profile.to_str().unwrap();
+    assert!(!is_read_only(&profile)?);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_is_read_only_with_missing_path_in_read_only_parent() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir()?;
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o555))?;
+    let profile = dir.path().join("missing");
+    let profile = profile.to_str().unwrap();
+    let result = is_read_only(&profile);
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755))?;
+    assert!(result?);
+    Ok(())
+  }
+
+  #[rstest]
+  #[case::same_system("system-1", true)]
+  #[case::other_system("system-2", false)]
+  #[case::missing_system("missing", false)]
+  fn test_is_current_system(#[case] target: &str, #[case] expected: bool) -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    fs::create_dir(dir.path().join("system-1"))?;
+    fs::create_dir(dir.path().join("system-2"))?;
+    let current_system = dir.path().join("current-system");
+    std::os::unix::fs::symlink(dir.path().join("system-1"), &current_system)?;
+    assert_eq!(is_current_system(&current_system, &dir.path().join(target)), expected);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_print_changelog_without_changelog() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    print_changelog(dir.path().display())
+  }
+
+  #[test_log::test]
+  fn test_print_changelog_with_unreadable_changelog() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    fs::create_dir(dir.path().join("darwin-changes"))?;
+    assert!(print_changelog(dir.path().display()).is_err());
+    Ok(())
+  }
+}
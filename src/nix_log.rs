@@ -0,0 +1,221 @@
+//! Minimal renderer of the nix `internal-json` log format, used when the build doesn't go through nom.
+
+use std::{
+  collections::HashMap,
+  io::{BufRead, Write},
+};
+
+use color_eyre::owo_colors::OwoColorize;
+use serde_json::Value;
+
+type Result<T> = color_eyre::Result<T>;
+
+/// `actFileTransfer` activity type of nix
+const ACT_FILE_TRANSFER: u64 = 101;
+/// `actBuild` activity type of nix
+const ACT_BUILD: u64 = 105;
+/// `actSubstitute` activity type of nix
+const ACT_SUBSTITUTE: u64 = 108;
+/// `resProgress` result type of nix
+const RES_PROGRESS: u64 = 105;
+/// Warnings and errors are the only messages rendered
+const MAX_MESSAGE_LEVEL: u64 = 1;
+
+/// An event of the nix `internal-json` log
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Event {
+  Start { id: u64, kind: u64, text: String },
+  Stop { id: u64 },
+  Progress { id: u64, done: u64, expected: u64 },
+  Message { level: u64, msg: String },
+}
+
+/// Parse a line of the `internal-json` log, optionally prefixed by `@nix `
+pub fn parse_line(line: &str) -> Option<Event> {
+  let json: Value = serde_json::from_str(line.trim().trim_start_matches("@nix ")).ok()?;
+  match json["action"].as_str()? {
+    "start" => {
+      Some(Event::Start {
+        id: json["id"].as_u64()?,
+        kind: json["type"].as_u64().unwrap_or_default(),
+        text: json["text"].as_str().unwrap_or_default().to_string(),
+      })
+    },
+    "stop" => Some(Event::Stop { id: json["id"].as_u64()? }),
+    "result" if json["type"].as_u64() == Some(RES_PROGRESS) => {
+      let fields = json["fields"].as_array()?;
+      Some(Event::Progress {
+        id: json["id"].as_u64()?,
+        done: fields.first()?.as_u64()?,
+        expected: fields.get(1)?.as_u64()?,
+      })
+    },
+    "msg" => {
+      Some(Event::Message { level: json["level"].as_u64()?, msg: strip_ansi_escapes::strip_str(json["msg"].as_str()?) })
+    },
+    _ => None,
+  }
+}
+
+/// Keeps track of the running activities to render the events as compact lines
+#[derive(Debug, Default)]
+pub struct Renderer {
+  activities: HashMap<u64, (u64, String)>,
+  /// Last rendered quarter of the progress of each download
+  progress: HashMap<u64, u64>,
+}
+
+impl Renderer {
+  /// Render an event, `None` if it isn't worth a line
+  pub fn render(&mut self, event: Event) -> Option<String> {
+    match event {
+      Event::Start { id, kind, text } => {
+        self.activities.insert(id, (kind, text.clone()));
+        matches!(kind, ACT_BUILD | ACT_SUBSTITUTE | ACT_FILE_TRANSFER)
+          .then_some(text)
+          .filter(|text| !text.is_empty())
+          .map(|text| format!("{} {}", ">".green(), text))
+      },
+      Event::Stop { id } => {
+        self.progress.remove(&id);
+        match self.activities.remove(&id) {
+          Some((ACT_BUILD, text)) if !text.is_empty() => Some(format!("{} {}", "✓".green(), text)),
+          _ => None,
+        }
+      },
+      Event::Progress { id, done, expected } => {
+        let (ACT_FILE_TRANSFER, text) = self.activities.get(&id)? else {
+          return None;
+        };
+        if expected == 0 {
+          return None;
+        }
+        let quarter = done * 4 / expected;
+        if self.progress.insert(id, quarter) == Some(quarter) || quarter == 0 {
+          return None;
+        }
+        Some(format!("{} {} {}%", "↓".blue(), text, quarter * 25))
+      },
+      Event::Message { level, msg } if level <= MAX_MESSAGE_LEVEL => Some(format!("{} {}", "!".red(), msg)),
+      Event::Message { .. } => None,
+    }
+  }
+}
+
+/// Render the `internal-json` log read from `reader` to `writer`, returning the raw log
+pub fn render_lines(reader: impl std::io::Read, writer: &mut impl Write) -> Result<String> {
+  let mut reader = std::io::BufReader::new(reader);
+  let mut renderer = Renderer::default();
+  let mut output = String::new();
+  let mut line = vec![];
+  while reader.read_until(b'\n', &mut line)? > 0 {
+    let text = String::from_utf8_lossy(&line);
+    match parse_line(&text) {
+      Some(event) => {
+        if let Some(rendered) = renderer.render(event) {
+          writeln!(writer, "{}", rendered)?;
+        }
+      },
+      // Lines that aren't part of the log are forwarded as is
+      None => write!(writer, "{}", text)?,
+    }
+    output.push_str(&text);
+    line.clear();
+  }
+  writer.flush()?;
+  Ok(output)
+}
+
+/// Render the log to `terminal` unless `quiet` is set, the raw log is returned either way for error reporting
+pub fn render_build_log(reader: impl std::io::Read, quiet: bool, terminal: &mut impl Write) -> Result<String> {
+  if quiet {
+    render_lines(reader, &mut std::io::sink())
+  } else {
+    render_lines(reader, terminal)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_eq;
+  use rstest::rstest;
+
+  use super::*;
+
+  #[rstest]
+  #[case::start(
+    r#"@nix {"action":"start","id":1,"level":3,"parent":0,"text":"building '/nix/store/x.drv'","type":105}"#,
+    Some(Event::Start { id: 1, kind: 105, text: "building '/nix/store/x.drv'".to_string() })
+  )]
+  #[case::stop(r#"@nix {"action":"stop","id":1}"#, Some(Event::Stop { id: 1 }))]
+  #[case::progress(
+    r#"@nix {"action":"result","fields":[512,1024,0,0],"id":2,"type":105}"#,
+    Some(Event::Progress { id: 2, done: 512, expected: 1024 })
+  )]
+  #[case::message(
+    r#"@nix {"action":"msg","level":0,"msg":"\u001b[31;1merror:\u001b[0m build failed"}"#,
+    Some(Event::Message { level: 0, msg: "error: build failed".to_string() })
+  )]
+  #[case::log_line(r#"@nix {"action":"result","fields":["compiling"],"id":1,"type":101}"#, None)]
+  #[case::plain_text("warning: Git tree is dirty", None)]
+  fn test_parse_line(#[case] line: &str, #[case] expected: Option<Event>) {
+    assert_eq!(parse_line(line), expected);
+  }
+
+  #[rstest]
+  #[case::quiet(true)]
+  #[case::terminal(false)]
+  #[test_log::test]
+  fn test_render_build_log(#[case] quiet: bool) -> Result<()> {
+    let log = concat!(
+      r#"@nix {"action":"start","id":3,"level":3,"parent":0,"text":"building 'x.drv'","type":105}"#,
+      "\n",
+      r#"@nix {"action":"msg","level":0,"msg":"error: builder for 'x.drv' failed"}"#,
+      "\n",
+    );
+    let mut terminal = vec![];
+    let output = render_build_log(log.as_bytes(), quiet, &mut terminal)?;
+    assert_eq!(output, log);
+    let rendered = String::from_utf8_lossy(&terminal);
+    if quiet {
+      assert_eq!(rendered, "");
+    } else {
+      assert!(rendered.contains("error: builder for 'x.drv' failed"), "{:?}", rendered);
+    }
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_render_lines() -> Result<()> {
+    let log = [
+      r#"@nix {"action":"start","id":1,"level":4,"parent":0,"text":"querying info","type":109}"#,
+      r#"@nix {"action":"start","id":2,"level":4,"parent":0,"text":"downloading 'https://cache'","type":101}"#,
+      r#"@nix {"action":"result","fields":[100,1000,0,0],"id":2,"type":105}"#,
+      r#"@nix {"action":"result","fields":[600,1000,0,0],"id":2,"type":105}"#,
+      r#"@nix {"action":"result","fields":[700,1000,0,0],"id":2,"type":105}"#,
+      r#"@nix {"action":"stop","id":2}"#,
+      r#"@nix {"action":"start","id":3,"level":3,"parent":0,"text":"building 'x.drv'","type":105}"#,
+      r#"@nix {"action":"stop","id":3}"#,
+      r#"@nix {"action":"msg","level":5,"msg":"debug"}"#,
+      r#"@nix {"action":"msg","level":1,"msg":"warning: dirty"}"#,
+      "",
+    ]
+    .join("\n");
+    let mut rendered = vec![];
+    let output = render_lines(log.as_bytes(), &mut rendered)?;
+    assert_eq!(output, log);
+    assert_eq!(
+      strip_ansi_escapes::strip_str(String::from_utf8(rendered)?),
+      [
+        "> downloading 'https://cache'",
+        "↓ downloading 'https://cache' 50%",
+        "> building 'x.drv'",
+        "✓ building 'x.drv'",
+        "! warning: dirty",
+        "",
+      ]
+      .join("\n")
+    );
+    Ok(())
+  }
+}
@@ -0,0 +1,101 @@
+use std::{
+  fs::{File, OpenOptions, TryLockError},
+  io,
+  path::{Path, PathBuf},
+  thread,
+  time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::eyre, owo_colors::OwoColorize, Section, SectionExt};
+use log::debug;
+
+type Result<T> = color_eyre::Result<T>;
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Advisory lock serializing the runs changing a profile, released when dropped
+#[derive(Debug)]
+pub struct ProfileLock {
+  _file: File,
+}
+
+/// Directory shared by every user, unlike `TMPDIR` which is per user on macOS
+const LOCK_DIR: &str = "/tmp";
+
+/// The lock file of `profile`, kept in `/tmp` since the profiles directory is owned by root
+pub fn lock_path(profile: &str) -> PathBuf {
+  let name = profile.trim_start_matches('/').replace('/', "-");
+  Path::new(LOCK_DIR).join(format!("{}-{}.lock", env!("CARGO_PKG_NAME"), name))
+}
+
+fn open(path: &Path) -> io::Result<File> {
+  match OpenOptions::new().create(true).truncate(false).write(true).open(path) {
+    // the lock file was created by another user, a read-only descriptor can still be locked
+    Err(e) if e.kind() == io::ErrorKind::PermissionDenied => File::open(path),
+    result => result,
+  }
+}
+
+/// Acquire the lock at `path`, waiting up to `timeout` while another process holds it
+pub fn acquire(path: &Path, timeout: Duration) -> Result<ProfileLock> {
+  debug!("Acquiring the profile lock {:?}", path);
+  let file = open(path).map_err(|e| eyre!("unable to open the profile lock {:?}", path).with_error(|| e))?;
+  let start = Instant::now();
+  loop {
+    match file.try_lock() {
+      Ok(()) => return Ok(ProfileLock { _file: file }),
+      Err(TryLockError::WouldBlock) if start.elapsed() < timeout => thread::sleep(RETRY_INTERVAL),
+      Err(TryLockError::WouldBlock) => {
+        return Err(eyre!("another darwin-rebuild is running"))
+          .with_section(|| path.display().to_string().header("Lock file:"))
+          .suggestion(format!("wait for it to finish or raise {}", "--lock-timeout".yellow()));
+      },
+      Err(TryLockError::Error(e)) => {
+        return Err(eyre!("unable to lock the profile lock {:?}", path).with_error(|| e));
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  #[test_log::test]
+  fn test_lock_path() {
+    assert_eq!(
+      lock_path("/nix/var/nix/profiles/system"),
+      Path::new("/tmp/darwin-rebuild-nix-var-nix-profiles-system.lock")
+    );
+  }
+
+  #[test_log::test]
+  fn test_lock_path_ignores_tmpdir() {
+    let before = lock_path("/nix/var/nix/profiles/system");
+    let tmpdir = std::env::var_os("TMPDIR");
+    // still the same directory, so the tests running concurrently are unaffected
+    std::env::set_var("TMPDIR", std::env::temp_dir().join("."));
+    let after = lock_path("/nix/var/nix/profiles/system");
+    match tmpdir {
+      Some(tmpdir) => std::env::set_var("TMPDIR", tmpdir),
+      None => std::env::remove_var("TMPDIR"),
+    }
+    assert_eq!(after, before);
+  }
+
+  #[test_log::test]
+  fn test_second_acquisition_fails_while_held() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("profile.lock");
+
+    let lock = acquire(&path, Duration::ZERO)?;
+    let err = acquire(&path, Duration::from_millis(200)).unwrap_err();
+    assert_eq!(err.to_string(), "another darwin-rebuild is running");
+
+    drop(lock);
+    acquire(&path, Duration::ZERO)?;
+    Ok(())
+  }
+}
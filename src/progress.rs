@@ -0,0 +1,56 @@
+use std::{io::IsTerminal, time::Duration};
+
+use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
+
+/// Start a spinner on stderr that clears itself when dropped.
+///
+/// No spinner is shown when stderr isn't a terminal, when debug logs would be interleaved with it, or with
+/// `--quiet` and `--json`.
+pub fn spinner(message: &'static str, verbose: bool, quiet: bool, json: bool) -> Option<ProgressBar> {
+  spinner_if(message, spinner_enabled(verbose, quiet, json, std::io::stderr().is_terminal()))
+}
+
+fn spinner_enabled(verbose: bool, quiet: bool, json: bool, is_terminal: bool) -> bool {
+  !(verbose || quiet || json) && is_terminal
+}
+
+fn spinner_if(message: &'static str, enabled: bool) -> Option<ProgressBar> {
+  if !enabled {
+    return None;
+  }
+  let spinner = ProgressBar::new_spinner()
+    .with_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").unwrap())
+    .with_message(message)
+    .with_finish(ProgressFinish::AndClear);
+  spinner.enable_steady_tick(Duration::from_millis(100));
+  Some(spinner)
+}
+
+#[cfg(test)]
+mod tests {
+  use rstest::rstest;
+
+  use super::*;
+
+  #[test_log::test]
+  fn test_spinner_is_not_created_when_not_a_terminal() {
+    assert!(spinner_if("Resolving flake metadata…", false).is_none());
+  }
+
+  #[test_log::test]
+  fn test_spinner_is_not_created_when_verbose() {
+    assert!(spinner("Resolving flake metadata…", true, false, false).is_none());
+  }
+
+  #[rstest]
+  #[case::terminal(false, false, false, true, true)]
+  #[case::not_a_terminal(false, false, false, false, false)]
+  #[case::verbose(true, false, false, true, false)]
+  #[case::quiet(false, true, false, true, false)]
+  #[case::json(false, false, true, true, false)]
+  fn test_spinner_enabled(
+    #[case] verbose: bool, #[case] quiet: bool, #[case] json: bool, #[case] is_terminal: bool, #[case] expected: bool,
+  ) {
+    assert_eq!(spinner_enabled(verbose, quiet, json, is_terminal), expected);
+  }
+}
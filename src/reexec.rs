@@ -0,0 +1,77 @@
+use std::{
+  env,
+  ffi::{OsStr, OsString},
+  path::Path,
+};
+
+use log::info;
+use subprocess::{Exec, ExitStatus};
+
+use crate::nix_commands::{self, ExecTrace};
+
+type Result<T> = color_eyre::Result<T>;
+
+/// Environment variable marking a re-executed process, so it never re-executes itself again
+const REEXEC_ENV: &str = "DARWIN_REBUILD_REEXEC";
+
+/// Whether the process should re-execute itself as root
+pub fn should_reexec(reexec_as_root: bool, is_root: bool, reexecuted: bool) -> bool {
+  reexec_as_root && !is_root && !reexecuted
+}
+
+fn reexec_cmd(exe: &OsStr, args: &[OsString], askpass: Option<&Path>) -> Exec {
+  // sudo resets the environment, so the marker is set by env as root
+  nix_commands::sudo_cmd(askpass).arg("env").arg(format!("{}=1", REEXEC_ENV)).arg(exe).args(args)
+}
+
+/// Re-execute darwin-rebuild as root with the same arguments, so sudo only prompts once.
+///
+/// Returns the exit code of the re-executed process, `None` if the current process should carry on.
+pub fn reexec_as_root(reexec_as_root: bool, askpass: Option<&Path>) -> Result<Option<i32>> {
+  if !should_reexec(reexec_as_root, nix_commands::is_root_user()?, env::var_os(REEXEC_ENV).is_some()) {
+    return Ok(None);
+  }
+  let exe = env::current_exe()?;
+  let args: Vec<OsString> = env::args_os().skip(1).collect();
+  info!("re-executing as root...");
+  let status = reexec_cmd(exe.as_os_str(), &args, askpass).trace().join()?;
+  Ok(Some(match status {
+    ExitStatus::Exited(code) => code as i32,
+    _ => 1,
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_eq;
+  use rstest::rstest;
+
+  use super::*;
+
+  #[rstest]
+  #[case::requested(true, false, false, true)]
+  #[case::not_requested(false, false, false, false)]
+  #[case::already_root(true, true, false, false)]
+  #[case::already_reexecuted(true, false, true, false)]
+  fn test_should_reexec(
+    #[case] reexec_as_root: bool, #[case] is_root: bool, #[case] reexecuted: bool, #[case] expected: bool,
+  ) {
+    assert_eq!(should_reexec(reexec_as_root, is_root, reexecuted), expected);
+  }
+
+  #[rstest]
+  #[case::without_askpass(
+    None,
+    "sudo env 'DARWIN_REBUILD_REEXEC=1' /run/current-system/sw/bin/darwin-rebuild switch --reexec-as-root"
+  )]
+  #[case::askpass(
+    Some("/usr/local/bin/askpass"),
+    "SUDO_ASKPASS=/usr/local/bin/askpass sudo -A env 'DARWIN_REBUILD_REEXEC=1' /run/current-system/sw/bin/darwin-rebuild \
+     switch --reexec-as-root"
+  )]
+  fn test_reexec_cmd_sets_marker(#[case] askpass: Option<&str>, #[case] expected: &str) {
+    let args = [OsString::from("switch"), OsString::from("--reexec-as-root")];
+    let cmd = reexec_cmd(OsStr::new("/run/current-system/sw/bin/darwin-rebuild"), &args, askpass.map(Path::new));
+    assert_eq!(cmd.to_cmdline_lossy(), expected);
+  }
+}
@@ -1,3 +1,4 @@
 mod nix_darwin_action;
 pub mod nix_darwin_runner;
+mod run_summary;
 pub mod runnable;
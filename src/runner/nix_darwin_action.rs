@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use crate::cli::Action;
 
 #[derive(Debug)]
@@ -10,7 +12,13 @@ pub(super) enum NixDarwinAction {
   Build,
   Check,
   Changelog,
-  Completions(clap_complete::Shell),
+  CurrentGeneration,
+  Generations,
+  Profiles,
+  Compare(u32, u32),
+  Init,
+  Completions(clap_complete::Shell, Option<PathBuf>),
+  Man(Option<PathBuf>),
 }
 
 impl From<Action> for NixDarwinAction {
@@ -22,7 +30,20 @@ impl From<Action> for NixDarwinAction {
       Action::Build => Self::Build,
       Action::Check => Self::Check,
       Action::Changelog => Self::Changelog,
-      Action::Completions(args) => Self::Completions(args.shell),
+      Action::CurrentGeneration => Self::CurrentGeneration,
+      Action::Generations => Self::Generations,
+      Action::Profiles => Self::Profiles,
+      Action::Compare(args) => Self::Compare(args.from, args.to),
+      Action::Init => Self::Init,
+      Action::Completions(args) => Self::Completions(args.shell, args.output),
+      Action::Man(args) => Self::Man(args.output),
     }
   }
 }
+
+impl NixDarwinAction {
+  /// Whether the action reports a run summary, query actions print their own output instead
+  pub(super) fn has_summary(&self) -> bool {
+    matches!(self, Self::Rollback | Self::Switch | Self::Activate | Self::Build | Self::Check)
+  }
+}
@@ -1,161 +1,685 @@
-use std::{env, ffi::OsStr, fmt::Display, path::Path};
+use std::{
+  env,
+  ffi::OsStr,
+  path::{Path, PathBuf},
+  time::{Duration, SystemTime},
+};
 
 use color_eyre::{
-  eyre::{bail, eyre},
+  eyre::{bail, eyre, WrapErr},
   owo_colors::OwoColorize,
+  Section,
 };
-use log::{debug, info};
+use log::{debug, info, warn};
 use regex::Regex;
 use subprocess::Exec;
 
 use crate::{
   cli::{Action, Cli},
-  nix_commands::{self, SetProfile},
-  print_bool, DEFAULT_PROFILE,
+  metadata_cache::MetadataCache,
+  nix_commands::{self, BuildOutcome, SetProfile},
+  print_bool, profile_lock, progress, state, CURRENT_SYSTEM, DEFAULT_PROFILE, SYSTEM_PROFILES,
 };
 
+/// What a switch does with the built configuration
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub(super) struct SwitchSteps {
+  /// Point the profile at the configuration, skipped by `--dry-activate`
+  pub set_profile: bool,
+  /// Activate the configuration, deferred by `--no-activate`
+  pub activate: bool,
+}
+
 pub struct NixDarwinRunner {
   pub(super) action: Option<Action>,
   pub(super) rollback: bool,
+  pub(super) preview: bool,
   pub(super) list_generations: bool,
   pub(super) profile: String,
   pub(super) extra_build_flags: Vec<String>,
   pub(super) flake: Option<String>,
   pub(super) flake_flags: Vec<String>,
   pub(super) flake_attr: String,
+  pub(super) hostnames: Vec<String>,
+  pub(super) fqdn: bool,
+  pub(super) nom: bool,
+  pub(super) strict_nom: bool,
+  pub(super) nvd: bool,
+  pub(super) out_path_only: bool,
+  pub(super) fast: bool,
+  pub(super) diff_summary: bool,
+  pub(super) pre_build: Option<String>,
+  pub(super) retries: u32,
+  pub(super) stall_warning: Duration,
+  pub(super) system_config: Option<String>,
+  pub(super) last: bool,
+  pub(super) keep_result: bool,
+  pub(super) why_depends: Option<String>,
+  pub(super) closure_size: bool,
+  pub(super) store_diff: bool,
+  pub(super) verbose_failures: bool,
+  pub(super) bell: bool,
+  pub(super) print_flake_attr: bool,
+  pub(super) no_link: bool,
+  pub(super) summary_only: bool,
+  pub(super) bell_threshold: Duration,
+  pub(super) force: bool,
+  pub(super) no_activate: bool,
+  pub(super) dry_activate: bool,
+  pub(super) json: bool,
+  pub(super) report: Option<PathBuf>,
+  pub(super) keep: Option<usize>,
+  pub(super) keep_since: Option<String>,
+  pub(super) message: Option<String>,
+  pub(super) keep_env_vars: Vec<String>,
+  pub(super) lock_timeout: Duration,
+  pub(super) sudo_askpass: Option<PathBuf>,
+  pub(super) flake_update_input: Option<String>,
+  pub(super) commit_lock: bool,
 }
 
 impl NixDarwinRunner {
   pub fn new(args: &Cli) -> color_eyre::Result<Self> {
-    let extra_metadata_flags = vec![];
-    let extra_build_flags = vec![];
-    let profile = Self::parse_profile(&args.profile_name)?;
+    let extra_metadata_flags = Self::parse_extra_metadata_flags(args);
+    let mut extra_build_flags = Self::parse_extra_build_flags(args);
+    extra_build_flags.extend(Self::parse_arg_json(&args.arg_json)?);
+    extra_build_flags.extend(Self::parse_arg_from_file(&args.arg_from_file)?);
+    let profile = Self::parse_profile(&args.profile_name, args.sudo_askpass.as_deref())?;
     debug!("Current profile: {}", profile.yellow());
 
     let flake_flags = vec!["--extra-experimental-features".to_string(), "nix-command flakes".to_string()];
-    let (flake, flake_attr) = Self::parse_flake(args, &flake_flags, &extra_metadata_flags)?;
+    let flake_value = match &args.flake {
+      Some(flake) => Some(flake.clone()),
+      None => {
+        match nix_commands::read_flake_file(&env::current_dir()?)? {
+          Some(flake) => Some(flake),
+          None if args.auto_flake => nix_commands::discover_flake(&env::current_dir()?)?,
+          None => None,
+        }
+      },
+    };
+    let (flake, hostnames) = Self::parse_flake(args, flake_value.as_deref(), &flake_flags, &extra_metadata_flags)?;
+    if hostnames.len() > 1 && args.action != Some(Action::Build) {
+      bail!("building multiple hostnames is only supported by the build action");
+    }
+    if args.why_depends.is_some() && !matches!(args.action, Some(Action::Build | Action::Check | Action::Switch)) {
+      bail!("--why-depends is only supported by the build, check and switch actions");
+    }
+    let flake_attr = hostnames.first().map(|hostname| Self::flake_attr_for(hostname)).unwrap_or_default();
 
     Ok(Self {
-      action: args.action,
+      action: args.action.clone(),
       rollback: args.rollback,
+      preview: args.preview,
       list_generations: args.list_generations,
       profile,
       extra_build_flags,
       flake_flags,
       flake,
       flake_attr,
+      hostnames,
+      fqdn: args.fqdn,
+      nom: !(args.no_nom || args.fast || args.out_path_only || args.no_link || args.summary_only || args.json),
+      strict_nom: args.strict_nom,
+      nvd: !(args.no_nvd || args.fast || args.out_path_only),
+      out_path_only: args.out_path_only,
+      fast: args.fast,
+      diff_summary: args.diff_summary,
+      pre_build: args.pre_build.clone(),
+      retries: args.retries,
+      stall_warning: Duration::from_secs(args.stall_warning),
+      system_config: args.system_config.clone(),
+      last: args.last,
+      keep_result: args.keep_result,
+      why_depends: args.why_depends.clone(),
+      closure_size: args.closure_size,
+      store_diff: args.store_diff,
+      verbose_failures: args.verbose_failures,
+      bell: args.bell,
+      print_flake_attr: args.print_flake_attr,
+      no_link: args.no_link,
+      summary_only: args.summary_only,
+      bell_threshold: Duration::from_secs(args.bell_threshold),
+      force: args.force,
+      no_activate: args.no_activate,
+      dry_activate: args.dry_activate,
+      json: args.json,
+      report: args.report.clone(),
+      keep: args.keep,
+      keep_since: args.keep_since.clone(),
+      message: args.message.clone(),
+      keep_env_vars: args.keep_env_vars.clone(),
+      lock_timeout: Duration::from_secs(args.lock_timeout),
+      sudo_askpass: args.sudo_askpass.clone(),
+      flake_update_input: args.flake_update_input.clone(),
+      commit_lock: args.commit_lock,
     })
   }
 
-  fn parse_profile(profile_name: &Option<String>) -> color_eyre::Result<String> {
-    fn default_value() -> String { env::var("profile").unwrap_or(DEFAULT_PROFILE.to_string()) }
+  fn parse_extra_metadata_flags(args: &Cli) -> Vec<String> {
+    let mut flags = vec![];
+    if let Some(flag) = Self::substitute_flag(args) {
+      flags.push(flag.to_string());
+    }
+    if args.offline {
+      flags.push("--offline".to_string());
+    }
+    if args.accept_flake_config {
+      flags.push("--accept-flake-config".to_string());
+    }
+    if args.refresh {
+      flags.push("--refresh".to_string());
+    }
+    if args.allow_dirty {
+      flags.extend(["--option", "warn-dirty", "false"].map(str::to_string));
+    }
+    if let Some(eval_cache) = args.eval_cache() {
+      flags.extend(["--option".to_string(), "eval-cache".to_string(), eval_cache.to_string()]);
+    }
+    flags.extend(Self::eval_flag(args).map(str::to_string));
+    flags.extend(Self::override_flake_flags(args));
+    if let Some(inputs_from) = &args.inputs_from {
+      flags.extend(["--inputs-from".to_string(), inputs_from.clone()]);
+    }
+    if let Some(flake_registry) = &args.flake_registry {
+      flags.extend(["--flake-registry".to_string(), flake_registry.display().to_string()]);
+    }
+    flags.extend(Self::store_flags(args));
+    flags
+  }
+
+  /// The metadata flags followed by the flags only `nix build` understands
+  fn parse_extra_build_flags(args: &Cli) -> Vec<String> {
+    let mut flags = Self::parse_extra_metadata_flags(args);
+    if let Some(builders) = &args.builders {
+      flags.extend(["--builders".to_string(), builders.clone()]);
+    }
+    flags.extend(args.extra_substituters.iter().flat_map(|url| ["--extra-substituters".to_string(), url.clone()]));
+    flags.extend(
+      args.extra_trusted_public_keys.iter().flat_map(|key| ["--extra-trusted-public-keys".to_string(), key.clone()]),
+    );
+    // The nix option takes a single space separated list
+    if !args.trusted_substituters.is_empty() {
+      flags.extend(["--trusted-substituters".to_string(), args.trusted_substituters.join(" ")]);
+    }
+    if let Some(system) = &args.system {
+      flags.extend(["--system".to_string(), system.clone()]);
+    }
+    if let Some(sandbox) = args.sandbox() {
+      flags.extend(["--option".to_string(), "sandbox".to_string(), sandbox.to_string()]);
+    }
+    if let Some(system_features) = &args.system_features {
+      flags.extend(["--system-features".to_string(), system_features.clone()]);
+    }
+    if let Some(max_jobs) = args.max_jobs {
+      flags.extend(["--max-jobs".to_string(), max_jobs.to_string()]);
+    }
+    if let Some(cores) = args.cores {
+      flags.extend(["--cores".to_string(), cores.to_string()]);
+    }
+    if let Some(build_timeout) = args.build_timeout {
+      flags.extend(["--timeout".to_string(), build_timeout.to_string()]);
+    }
+    if let Some(max_silent_time) = args.max_silent_time {
+      flags.extend(["--max-silent-time".to_string(), max_silent_time.to_string()]);
+    }
+    flags.extend((0..args.verbose_nix).map(|_| "-v".to_string()));
+    flags
+  }
+
+  /// Converts `--arg-json NAME JSON` pairs into `--arg NAME (builtins.fromJSON "JSON")` flags.
+  ///
+  /// The JSON is validated first so malformed values fail before nix is invoked.
+  fn parse_arg_json(arg_json: &[String]) -> color_eyre::Result<Vec<String>> {
+    let mut flags = vec![];
+    for pair in arg_json.chunks(2) {
+      let [name, json] = pair else {
+        bail!("--arg-json expects a name and a value");
+      };
+      serde_json::from_str::<serde_json::Value>(json)
+        .wrap_err_with(|| format!("invalid JSON for argument {}", name.yellow()))?;
+      let escaped = json.replace('\\', "\\\\").replace('"', "\\\"").replace("${", "\\${");
+      flags.extend(["--arg".to_string(), name.clone(), format!("(builtins.fromJSON \"{}\")", escaped)]);
+    }
+    Ok(flags)
+  }
+
+  /// Converts `--arg-from-file NAME PATH` pairs into `--arg NAME <content of PATH>` flags
+  fn parse_arg_from_file(arg_from_file: &[String]) -> color_eyre::Result<Vec<String>> {
+    let mut flags = vec![];
+    for pair in arg_from_file.chunks(2) {
+      let [name, path] = pair else {
+        bail!("--arg-from-file expects a name and a path");
+      };
+      let content = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("unable to read argument {} from {}", name.yellow(), path.yellow()))?;
+      flags.extend(["--arg".to_string(), name.clone(), content.trim_end().to_string()]);
+    }
+    Ok(flags)
+  }
+
+  fn eval_flag(args: &Cli) -> Option<&'static str> {
+    if args.pure_eval {
+      Some("--pure-eval")
+    } else if args.impure {
+      Some("--impure")
+    } else {
+      None
+    }
+  }
+
+  fn store_flags(args: &Cli) -> Vec<String> {
+    let mut flags = vec![];
+    if let Some(store) = &args.store {
+      flags.extend(["--store".to_string(), store.clone()]);
+    }
+    if let Some(eval_store) = &args.eval_store {
+      flags.extend(["--eval-store".to_string(), eval_store.clone()]);
+    }
+    flags
+  }
+
+  fn override_flake_flags(args: &Cli) -> impl Iterator<Item = String> + '_ {
+    args.override_flake.chunks(2).flat_map(|pair| std::iter::once("--override-flake".to_string()).chain(pair.to_vec()))
+  }
+
+  fn substitute_flag(args: &Cli) -> Option<&'static str> {
+    args.substitute().map(|substitute| if substitute { "--substitute" } else { "--no-substitute" })
+  }
+
+  /// Resolves the profile path.
+  ///
+  /// Precedence is: an explicit `--profile-name` always wins, then the `profile` environment variable, then
+  /// [`DEFAULT_PROFILE`].
+  fn parse_profile(profile_name: &Option<String>, askpass: Option<&Path>) -> color_eyre::Result<String> {
     debug!("looking for profile... {:?}", profile_name.yellow());
-    let result = match &profile_name {
-      Some(profile_name) if profile_name != "system" => {
+    if let Some(name) = profile_name {
+      Self::validate_profile_name(name)?;
+    }
+    let profile = Self::resolve_profile(profile_name.as_deref(), env::var("profile").ok());
+    if profile.is_empty() {
+      bail!("profile is empty");
+    }
+    if matches!(profile_name.as_deref(), Some(name) if name != "system") {
+      let path = Path::new(&profile).parent().ok_or(eyre!("unable to get parent directory of {}", profile.yellow()))?;
+      nix_commands::create_dir_all(path, askpass)?;
+    }
+    Ok(profile)
+  }
+
+  /// The profile name is a file name in `system-profiles`, so it must not escape that directory
+  fn validate_profile_name(name: &str) -> color_eyre::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(std::path::is_separator) {
+      return Err(
+        eyre!("invalid profile name {}", name.yellow())
+          .suggestion("the profile name must not contain path separators or be `.` or `..`"),
+      );
+    }
+    Ok(())
+  }
+
+  fn resolve_profile(profile_name: Option<&str>, env_profile: Option<String>) -> String {
+    match profile_name {
+      Some("system") => DEFAULT_PROFILE.to_string(),
+      Some(profile_name) => {
         debug!("looking for custom profile {}", profile_name.yellow());
-        let profile = format!("/nix/var/nix/profiles/system-profiles/{}", profile_name);
-        let path =
-          Path::new(&profile).parent().ok_or(eyre!("unable to get parent directory of {}", profile.yellow()))?;
-        std::fs::create_dir_all(path)?;
-        Ok(profile)
+        format!("{}/{}", SYSTEM_PROFILES, profile_name)
       },
-      _ => Ok(default_value()),
-    };
-    result.and_then(|e| if e.is_empty() { bail!("profile is empty") } else { Ok(e) })
+      None => env_profile.unwrap_or(DEFAULT_PROFILE.to_string()),
+    }
   }
 
   fn parse_flake(
-    args: &Cli, flake_flags: &[String], extra_metadata_flags: &[String],
-  ) -> color_eyre::Result<(Option<String>, String)> {
-    if let Some(flake_value) = &args.flake {
+    args: &Cli, flake_value: Option<&str>, flake_flags: &[String], extra_metadata_flags: &[String],
+  ) -> color_eyre::Result<(Option<String>, Vec<String>)> {
+    if args.no_flake {
+      debug!("Flakes disabled by --no-flake");
+      return Ok((None, vec![]));
+    }
+    if let Some(flake_value) = flake_value {
       debug!("Looking for flake metadata... {flake_value}");
       let re = Regex::new(r"^(([^:/?#]+):)?(//([^/?#]*))?([^?#]*)(\?([^#]*))?(#(.*))?")?;
 
-      let (flake, flake_attr) = if let Some(caps) = re.captures(flake_value) {
+      let (flake, hostnames) = if let Some(caps) = re.captures(flake_value) {
         let scheme = if let Some(r) = caps.get(1) { r.as_str() } else { "" };
         let authority = if let Some(e) = caps.get(3) { e.as_str() } else { "" };
         let path = if let Some(e) = caps.get(5) { e.as_str() } else { "" };
         let query_with_question = if let Some(e) = caps.get(6) { e.as_str() } else { "" };
-        let flake_attr = if let Some(e) = caps.get(9) {
-          e.as_str().to_string()
+        let hostnames = Self::parse_hostnames(&args.hostname, caps.get(9).map(|e| e.as_str()), args.fqdn)?;
+        let flake_ref = Self::normalize_flake_ref(scheme, authority, path)?;
+        let flake_value = format!("{}{}", flake_ref, query_with_question);
+        let mut cache = args
+          .cache_flake_metadata
+          .as_deref()
+          .map(|path| MetadataCache::load(path, Duration::from_secs(args.cache_flake_metadata_ttl)));
+        let now = SystemTime::now();
+        let cached = if args.refresh { None } else { cache.as_ref().and_then(|cache| cache.get(&flake_value, now)) };
+        let metadata = if let Some(metadata) = cached {
+          metadata.clone()
         } else {
-          match nix_commands::get_local_hostname() {
+          let cmd = if nix_commands::nix_command_supports_flake_metadata(flake_flags) { "metadata" } else { "info" };
+          let spinner = progress::spinner("Resolving flake metadata…", args.verbose, args.quiet, args.json);
+          let metadata = nix_commands::retry(args.retries, || {
+            nix_commands::get_flake_metadata(&flake_value, cmd, flake_flags, extra_metadata_flags)
+          });
+          drop(spinner);
+          let metadata = match metadata {
             Ok(e) => e,
-            Err(err) => bail!("Failed to get local hostname: {:?}", err),
+            Err(err) => return Err(eyre!("Failed to get flake metadata: {:?}", err)),
+          };
+          if let Some(cache) = &mut cache {
+            cache.insert(&flake_value, metadata.clone(), now);
+            cache.save()?;
           }
+          metadata
         };
-        let flake_value = format!("{}{}{}{}", scheme, authority, path, query_with_question);
-        let cmd = if nix_commands::nix_command_supports_flake_metadata(flake_flags) { "metadata" } else { "info" };
-
-        let metadata = match nix_commands::get_flake_metadata(&flake_value, cmd, flake_flags, extra_metadata_flags) {
-          Ok(e) => e,
-          Err(err) => bail!("Failed to get flake metadata: {:?}", err),
-        };
+        if let Some(warning) = Self::dirty_warning(&metadata, args.allow_dirty) {
+          warn!("{}", warning);
+        }
+        let input = flake_value;
         let url = &metadata["url"];
         debug!("Url {:?}", url.blue());
         let flake_value = match url {
-          serde_json::Value::String(e) if e.is_empty() => bail!("flake url is empty"),
+          serde_json::Value::String(e) if e.is_empty() => return Err(eyre!("flake url is empty")),
           serde_json::Value::String(e) if !e.is_empty() => e,
-          _ => bail!("flake url is not a string"),
+          _ => return Err(eyre!("flake url is not a string")),
         }
         .to_owned();
         debug!("flake_value: {:?}", flake_value.blue());
-        let flake = match &metadata["resolved"]["submodules"] {
-          serde_json::Value::String(str) => {
-            let value: bool = str.parse()?;
-            if value {
-              if flake_value.contains('?') {
-                format!("{}&submodules=1", flake_value)
-              } else {
-                format!("{}?submodules=1", flake_value)
-              }
-            } else {
-              flake_value
-            }
-          },
-          serde_json::Value::Bool(true) => {
-            if flake_value.contains('?') {
-              format!("{}&submodules=1", flake_value)
-            } else {
-              format!("{}?submodules=1", flake_value)
-            }
-          },
-          serde_json::Value::Bool(false) => flake_value,
-          serde_json::Value::Null => flake_value,
-          val => bail!("submodules is not a boolean {}", val.red().bold()),
+        if !args.quiet && Self::is_flake_rewritten(&input, &flake_value) {
+          info!("{} resolved to {}", input.yellow(), flake_value.yellow());
+        }
+        let flake = if Self::parse_submodules(&metadata["resolved"]["submodules"])? {
+          if flake_value.contains('?') {
+            format!("{}&submodules=1", flake_value)
+          } else {
+            format!("{}?submodules=1", flake_value)
+          }
+        } else {
+          flake_value
         };
         debug!("flake: {:?}", flake.blue());
 
-        (Some(flake), flake_attr)
+        (Some(flake), hostnames)
       } else {
-        (None, "".to_string())
+        (None, vec![])
       };
 
-      Ok((flake, format!("darwinConfigurations.{}", flake_attr)))
+      Ok((flake, hostnames))
+    } else {
+      Ok((None, vec![]))
+    }
+  }
+
+  /// Describes the uncommitted changes of a local flake, unless they are allowed with `--allow-dirty`
+  fn dirty_warning(metadata: &serde_json::Value, allow_dirty: bool) -> Option<String> {
+    let dirty_revision = metadata["dirtyRevision"].as_str()?;
+    if allow_dirty {
+      debug!("building the dirty revision {}", dirty_revision);
+      return None;
+    }
+    Some(format!(
+      "the flake has uncommitted changes ({}), they are included in the build, pass --allow-dirty to silence this",
+      dirty_revision.yellow()
+    ))
+  }
+
+  /// Checks if nix resolved the flake to a different URL, ignoring the query and trailing slashes
+  fn is_flake_rewritten(input: &str, resolved: &str) -> bool {
+    let base = |url: &str| url.split('?').next().unwrap_or_default().trim_end_matches('/').to_string();
+    base(input) != base(resolved)
+  }
+
+  /// Coerces the `submodules` metadata to a boolean, nix emits it as a bool, a number or a string.
+  fn parse_submodules(value: &serde_json::Value) -> color_eyre::Result<bool> {
+    match value {
+      serde_json::Value::Bool(value) => Ok(*value),
+      serde_json::Value::Null => Ok(false),
+      serde_json::Value::Number(number) => {
+        match number.as_u64() {
+          Some(0) => Ok(false),
+          Some(1) => Ok(true),
+          _ => Err(eyre!("submodules is not a boolean {}", number.red().bold())),
+        }
+      },
+      serde_json::Value::String(str) => {
+        match str.as_str() {
+          "1" => Ok(true),
+          "0" => Ok(false),
+          str => str.parse().map_err(|_| eyre!("submodules is not a boolean {}", str.red().bold())),
+        }
+      },
+      val => Err(eyre!("submodules is not a boolean {}", val.red().bold())),
+    }
+  }
+
+  /// Normalizes a flake reference, a local directory without a scheme gets the `path:` scheme.
+  fn normalize_flake_ref(scheme: &str, authority: &str, path: &str) -> color_eyre::Result<String> {
+    let path = Self::canonicalize_flake_path(scheme, authority, path)?;
+    let scheme =
+      if scheme.is_empty() && Path::new(&path).is_absolute() && Path::new(&path).is_dir() { "path:" } else { scheme };
+    Ok(format!("{}{}{}", scheme, authority, path))
+  }
+
+  /// Canonicalizes local flake paths, so nix resolves them from where the tool was invoked.
+  ///
+  /// Plain paths are only local when they start with a `.` or a `/`, otherwise they are flake registry references.
+  fn canonicalize_flake_path(scheme: &str, authority: &str, path: &str) -> color_eyre::Result<String> {
+    let is_local = match scheme {
+      "" => path.starts_with('.') || path.starts_with('/'),
+      "path:" | "git+file:" => authority.is_empty() && !path.is_empty() && !Path::new(path).is_absolute(),
+      _ => false,
+    };
+    if is_local {
+      let real_path = nix_commands::get_real_path(&path)?;
+      debug!("canonicalized flake path {} to {}", path.yellow(), real_path.yellow());
+      Ok(real_path)
     } else {
-      Ok((None, "".to_string()))
+      Ok(path.to_string())
+    }
+  }
+
+  /// Resolves the hostnames to build.
+  ///
+  /// Explicit `--hostname` values win over the flake attribute, which wins over the local hostname.
+  fn parse_hostnames(hostnames: &[String], flake_attr: Option<&str>, fqdn: bool) -> color_eyre::Result<Vec<String>> {
+    if !hostnames.is_empty() {
+      return Ok(hostnames.to_vec());
+    }
+    match flake_attr {
+      Some(flake_attr) => Ok(vec![flake_attr.to_string()]),
+      None => {
+        match nix_commands::get_local_hostname(fqdn) {
+          Ok(e) => Ok(vec![e]),
+          Err(err) => Err(eyre!("Failed to get local hostname: {:?}", err)),
+        }
+      },
     }
   }
 
-  pub(super) fn build_configuration(
-    &self, out_dir: &(impl AsRef<str> + Into<String> + Display),
-  ) -> color_eyre::Result<String> {
+  fn flake_attr_for(hostname: &str) -> String { format!("darwinConfigurations.{}", hostname) }
+
+  pub(super) fn build_configuration(&self, out_link: Option<&str>) -> color_eyre::Result<BuildOutcome> {
     if let Some(flake) = &self.flake {
       info!("building the system configuration from {}...", flake.yellow());
-      nix_commands::nix_flake_build(flake, &self.flake_attr, &self.flake_flags, out_dir, &self.extra_build_flags)
+      let nom = self.use_nom(env::var_os("PATH").as_deref())?;
+      let mut outcome = self.build_flake_attr(flake, &self.flake_attr, out_link, nom)?;
+      outcome.diffed = self.print_diff(&outcome.out_path)?;
+      Ok(outcome)
     } else {
       info!("building the system configuration from <darwin>...");
-      nix_commands::nix_build("<darwin>", "system", out_dir, &self.extra_build_flags)
+      nix_commands::nix_build("<darwin>", "system", out_link, &self.extra_build_flags)
+    }
+  }
+
+  /// Checks that nom can be found in `path` when the logs should go through it.
+  ///
+  /// A missing nom falls back to plain nix logs, unless `--strict-nom` is given.
+  pub(super) fn use_nom(&self, path: Option<&OsStr>) -> color_eyre::Result<bool> {
+    if !self.nom || nix_commands::find_executable("nom", path).is_some() {
+      return Ok(self.nom);
+    }
+    if self.strict_nom {
+      return Err(eyre!("nom is not installed or not in $PATH"))
+        .suggestion("install nix-output-monitor or build without --strict-nom");
+    }
+    warn!("nom is not installed, falling back to plain nix logs");
+    Ok(false)
+  }
+
+  /// Builds `flake_attr`, retrying transient failures and rebuilding verbosely on failure with `--verbose-failures`
+  fn build_flake_attr(
+    &self, flake: &String, flake_attr: &String, out_link: Option<&str>, nom: bool,
+  ) -> color_eyre::Result<BuildOutcome> {
+    nix_commands::with_verbose_retry(
+      self.verbose_failures,
+      || {
+        nix_commands::retry(self.retries, || {
+          nix_commands::nix_flake_build(
+            flake,
+            flake_attr,
+            &self.flake_flags,
+            out_link,
+            &self.extra_build_flags,
+            self.build_logs(nom),
+          )
+        })
+      },
+      || nix_commands::nix_flake_build_verbose(flake, flake_attr, &self.flake_flags, out_link, &self.extra_build_flags),
+    )
+  }
+
+  /// Builds the configuration of every hostname, returning each hostname with its store path
+  pub(super) fn build_configurations(&self, out_dir: Option<&Path>) -> color_eyre::Result<Vec<(String, String)>> {
+    let Some(flake) = &self.flake else {
+      bail!("building multiple hostnames requires a flake");
+    };
+    let nom = self.use_nom(env::var_os("PATH").as_deref())?;
+    self
+      .hostnames
+      .iter()
+      .map(|hostname| {
+        info!("building the system configuration of {} from {}...", hostname.purple(), flake.yellow());
+        let out_link = out_dir
+          .map(|out_dir| {
+            let out_link = out_dir.join(format!("result-{}", hostname));
+            out_link.to_str().map(str::to_string).ok_or(eyre!("unable to convert {:?} to a string", out_link))
+          })
+          .transpose()?;
+        let flake_attr = Self::flake_attr_for(hostname);
+        let out_path = self.build_flake_attr(flake, &flake_attr, out_link.as_deref(), nom)?.out_path;
+        self.print_diff(&out_path)?;
+        Ok((hostname.clone(), nix_commands::get_real_path(&out_path)?))
+      })
+      .collect()
+  }
+
+  /// Prints the closure diff of `system_config` with the current system, returning whether it was printed
+  pub(super) fn print_diff(&self, system_config: &str) -> color_eyre::Result<bool> {
+    if self.store_diff {
+      debug!("build succedded, printing the store diff");
+      nix_commands::store_diff_closures(CURRENT_SYSTEM, system_config, &self.flake_flags)?;
+      return Ok(true);
+    }
+    if !self.nvd {
+      debug!("build succedded, skipping diff");
+      return Ok(false);
+    }
+    debug!("build succedded, printing diff");
+    if let Some(summary) = nix_commands::nvd_diff(DEFAULT_PROFILE, system_config, self.diff_summary)? {
+      info!("{}", summary.bold());
+    }
+    Ok(true)
+  }
+
+  /// Points the `result` symlink of the state directory at the store path of `out_path` with `--keep-result`
+  pub(super) fn keep_result(&self, out_path: &str) -> color_eyre::Result<()> {
+    if self.keep_result {
+      let link = state::update_result_link(&state::state_dir()?, &nix_commands::get_real_path(&out_path)?)?;
+      debug!("updated {}", link.display().yellow());
+    }
+    Ok(())
+  }
+
+  /// Shows why `out_path` depends on the package given with `--why-depends`
+  pub(super) fn show_why_depends(&self, out_path: &str) -> color_eyre::Result<()> {
+    if let Some(pkg) = &self.why_depends {
+      nix_commands::why_depends(out_path, pkg, &self.flake_flags)?;
+    }
+    Ok(())
+  }
+
+  /// Prints the closure size of `out_path` with `--closure-size`
+  pub(super) fn show_closure_size(&self, out_path: &str) -> color_eyre::Result<()> {
+    if self.closure_size {
+      let size = nix_commands::closure_size(out_path, &self.flake_flags)?;
+      info!("closure size: {}", nix_commands::format_size(size).bold());
+    }
+    Ok(())
+  }
+
+  /// Attaches the notes given with `--message` to the generations of the profile
+  pub(super) fn with_notes(&self, mut generations: Vec<nix_commands::Generation>) -> Vec<nix_commands::Generation> {
+    if let Ok(state_dir) = state::state_dir() {
+      for generation in &mut generations {
+        generation.note = state::read_generation_note(&state_dir, &self.profile, generation.number);
+      }
+    }
+    generations
+  }
+
+  /// Prints the generation a rollback would switch to and its diff with the current generation
+  pub(super) fn preview_rollback(&self) -> color_eyre::Result<()> {
+    let generations = nix_commands::list_generations_detailed(&self.profile)?;
+    let current = generations
+      .iter()
+      .find(|generation| generation.current)
+      .ok_or(eyre!("unable to find the current generation of {}", self.profile.yellow()))?;
+    let Some(target) = nix_commands::rollback_target(&generations) else {
+      bail!("no generation older than {} to roll back to", current.number);
+    };
+    info!("rolling back would switch from generation {} to {}", current.number, target.number.bold());
+    if let (Some(from), Some(to)) = (&current.store_path, &target.store_path) {
+      if let Some(summary) = nix_commands::nvd_diff(from, to, self.diff_summary)? {
+        info!("{}", summary.bold());
+      }
+    }
+    Ok(())
+  }
+
+  /// Describes the generation change of a switch, `old_generation` is `None` on the first switch
+  pub(super) fn generation_transition(old_generation: Option<u32>, new_generation: u32) -> String {
+    match old_generation {
+      Some(old_generation) => format!("switched from generation {} to {}", old_generation, new_generation),
+      None => format!("switched to generation {}", new_generation),
+    }
+  }
+
+  pub(super) fn print_build_summary(results: &[(String, String)]) {
+    let width = results.iter().map(|(hostname, _)| hostname.len()).max().unwrap_or_default();
+    println!("{:<width$}  {}", "HOST".bold(), "STORE PATH".bold());
+    for (hostname, store_path) in results {
+      println!("{:<width$}  {}", hostname.purple(), store_path);
     }
   }
 
+  /// Lock the profile so concurrent runs don't change it at the same time
+  /// How the build logs are shown, `--summary-only` keeps them off the terminal
+  pub(super) fn build_logs(&self, nom: bool) -> nix_commands::BuildLogs {
+    nix_commands::BuildLogs { nom, quiet: self.summary_only, stall_warning: self.stall_warning }
+  }
+
+  pub(super) fn lock_profile(&self) -> color_eyre::Result<profile_lock::ProfileLock> {
+    profile_lock::acquire(&profile_lock::lock_path(&self.profile), self.lock_timeout)
+  }
+
   pub(super) fn switch_profile(&self, system_config: &impl AsRef<OsStr>) -> color_eyre::Result<()> {
     let is_root_user = nix_commands::is_root_user()?;
     let is_read_only = nix_commands::is_read_only(&self.profile)?;
     debug!("Is root user: {} is ro {}", print_bool!(is_root_user), print_bool!(is_read_only));
     if !is_root_user && is_read_only {
       info!("setting the profile as root...");
-      <() as SetProfile>::sudo_nix_env_set_profile(&self.profile, &system_config)?;
+      <() as SetProfile>::sudo_nix_env_set_profile(&self.profile, &system_config, self.sudo_askpass.as_deref())?;
     } else {
       info!("setting the profile...");
       <() as SetProfile>::nix_env_set_profile(&self.profile, &system_config)?;
@@ -163,6 +687,20 @@ impl NixDarwinRunner {
     Ok(())
   }
 
+  /// The `nix-env` command run on the profile, through sudo when `sudo` is set
+  fn profile_cmd(&self, extra_profile_flags: &[impl AsRef<OsStr>], sudo: bool) -> Exec {
+    let cmd =
+      if sudo { nix_commands::sudo_cmd(self.sudo_askpass.as_deref()).arg("nix-env") } else { Exec::cmd("nix-env") };
+    cmd.arg("-p").arg(&self.profile).args(extra_profile_flags)
+  }
+
+  /// The `nix-env` flags deleting `generations` from the profile
+  pub(super) fn delete_generations_flags(generations: &[u32]) -> Vec<String> {
+    let mut flags = vec!["--delete-generations".to_string()];
+    flags.extend(generations.iter().map(u32::to_string));
+    flags
+  }
+
   pub(super) fn run_profile<ExtraProfileFlags: AsRef<OsStr>>(
     &self, extra_profile_flags: &[ExtraProfileFlags],
   ) -> color_eyre::Result<()> {
@@ -171,11 +709,7 @@ impl NixDarwinRunner {
     let is_root_user = nix_commands::is_root_user()?;
     let is_read_only = nix_commands::is_read_only(&profile)?;
     debug!("Is root user: {} is ro {}", print_bool!(is_root_user), print_bool!(is_read_only));
-    let status = if !is_root_user && is_read_only {
-      Exec::cmd("sudo").arg("nix-env").arg("-p").arg(profile).args(extra_profile_flags).trace().join()
-    } else {
-      Exec::cmd("nix-env").arg("-p").arg(profile).args(extra_profile_flags).trace().join()
-    };
+    let status = self.profile_cmd(extra_profile_flags, !is_root_user && is_read_only).trace().join();
 
     if status.is_ok_and(|status| status.success()) {
       Ok(())
@@ -184,12 +718,57 @@ impl NixDarwinRunner {
     }
   }
 
-  pub(super) fn activate_profile(system_config: &impl std::fmt::Display) -> color_eyre::Result<()> {
+  /// Returns the system configuration to activate.
+  ///
+  /// An explicit `--system-config` is used as is, `--last` uses the last successful build, otherwise it is read from
+  /// the `systemConfig` file of the profile.
+  pub(super) fn activation_system_config(&self) -> color_eyre::Result<String> {
+    if let Some(system_config) = &self.system_config {
+      return Ok(system_config.clone());
+    }
+    if self.last {
+      return state::read_last_build(&state::state_dir()?);
+    }
+    nix_commands::profile_system_config(&self.profile)
+      .suggestion("use --system-config to specify the system configuration to activate")
+  }
+
+  /// The steps a switch runs once the configuration is built
+  pub(super) fn switch_steps(&self) -> SwitchSteps {
+    SwitchSteps { set_profile: !self.dry_activate, activate: !self.no_activate }
+  }
+
+  /// The system activation is skipped when `system_config` is already the current system, unless `--force` is given
+  fn should_activate_system(&self, current_system: &str, system_config: &str) -> bool {
+    self.force || !nix_commands::is_current_system(&current_system, &system_config)
+  }
+
+  /// The activation commands of `system_config`, printed by `--dry-activate` since nix-darwin has no dry activation
+  pub(super) fn dry_activation_cmds(&self, system_config: &str, is_root: bool) -> Vec<String> {
+    let mut cmds = vec![nix_commands::activate_user_cmd(&system_config, false).to_cmdline_lossy()];
+    if self.should_activate_system(CURRENT_SYSTEM, system_config) {
+      let cmd =
+        nix_commands::activate_system_cmd(&system_config, !is_root, &self.keep_env_vars, self.sudo_askpass.as_deref());
+      cmds.push(cmd.to_cmdline_lossy());
+    }
+    cmds
+  }
+
+  pub(super) fn activate_profile(&self, system_config: &impl std::fmt::Display) -> color_eyre::Result<()> {
+    if self.dry_activate {
+      info!("dry activation, would run:");
+      for cmd in self.dry_activation_cmds(&system_config.to_string(), nix_commands::is_root_user()?) {
+        info!("  {}", cmd.yellow());
+      }
+      return Ok(());
+    }
     info!("activating user profile...");
-    nix_commands::exec_activate_user(&system_config)?;
-    if !nix_commands::is_root_user()? {
+    nix_commands::exec_activate_user(&system_config, false)?;
+    if !self.should_activate_system(CURRENT_SYSTEM, &system_config.to_string()) {
+      info!("{} is already active, skipping system activation", system_config.yellow());
+    } else if !nix_commands::is_root_user()? {
       info!("activating system as root...");
-      nix_commands::sudo_exec_activate(&system_config)?;
+      nix_commands::sudo_exec_activate(&system_config, &self.keep_env_vars, self.sudo_askpass.as_deref())?;
     } else {
       info!("activating system...");
       nix_commands::exec_activate(&system_config)?;
@@ -199,9 +778,12 @@ impl NixDarwinRunner {
 }
 
 pub(crate) mod completion {
+  use std::path::Path;
+
   use clap::CommandFactory;
   use clap_complete::Shell;
-  use log::debug;
+  use color_eyre::{eyre::eyre, Section};
+  use log::{debug, info};
 
   use crate::cli::Cli;
 
@@ -215,25 +797,107 @@ pub(crate) mod completion {
     generate(gen, cmd, cmd.get_name().to_string(), &mut buf);
   }
 
-  pub(crate) fn generate_completion(shell: Shell) -> color_eyre::Result<()> {
+  pub(crate) fn generate_completion(shell: Shell, output: Option<&Path>) -> color_eyre::Result<()> {
     let mut cmd = Cli::command();
     debug!("Generating completions for shell: {}", shell);
-    print_completions(shell, &mut cmd);
+    match output {
+      Some(dir) => {
+        let name = cmd.get_name().to_string();
+        let path = clap_complete::generate_to(shell, &mut cmd, name, dir)
+          .map_err(|e| eyre!("unable to write the {} completion script into {:?}", shell, dir).with_error(|| e))?;
+        info!("Wrote {} completions to {}", shell, path.display());
+      },
+      None => print_completions(shell, &mut cmd),
+    }
     Ok(())
   }
+
+  #[cfg(test)]
+  mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::zsh(Shell::Zsh, "_darwin-rebuild")]
+    #[case::fish(Shell::Fish, "darwin-rebuild.fish")]
+    fn test_generate_completion_to_output(#[case] shell: Shell, #[case] file_name: &str) -> color_eyre::Result<()> {
+      let dir = tempfile::tempdir()?;
+      generate_completion(shell, Some(dir.path()))?;
+      let entries = std::fs::read_dir(dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+      assert_eq!(entries, [file_name]);
+      Ok(())
+    }
+  }
+}
+
+pub(crate) mod man {
+  use std::{fs, io::Write, path::Path};
+
+  use clap::CommandFactory;
+  use color_eyre::{eyre::eyre, Section};
+  use log::{debug, info};
+
+  use crate::cli::Cli;
+
+  fn render_man(out: &mut impl Write) -> std::io::Result<()> { clap_mangen::Man::new(Cli::command()).render(out) }
+
+  pub(crate) fn generate_man(output: Option<&Path>) -> color_eyre::Result<()> {
+    debug!("Generating the man page");
+    let mut buf = vec![];
+    render_man(&mut buf)?;
+    match output {
+      Some(path) => {
+        fs::write(path, buf).map_err(|e| eyre!("unable to write the man page to {:?}", path).with_error(|| e))?;
+        info!("Wrote the man page to {}", path.display());
+      },
+      #[cfg(not(test))]
+      None => std::io::stdout().write_all(&buf)?,
+      #[cfg(test)]
+      None => std::io::sink().write_all(&buf)?,
+    }
+    Ok(())
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+
+    #[test_log::test]
+    fn test_render_man() -> color_eyre::Result<()> {
+      let mut buf = vec![];
+      render_man(&mut buf)?;
+      let roff = String::from_utf8(buf)?;
+      assert!(roff.contains(".TH darwin-rebuild 1"), "{roff}");
+      assert!(roff.contains(".SH SUBCOMMANDS"), "{roff}");
+      assert!(roff.contains(r"darwin\-rebuild\-switch(1)"), "{roff}");
+      Ok(())
+    }
+
+    #[test_log::test]
+    fn test_generate_man_to_output() -> color_eyre::Result<()> {
+      let dir = tempfile::tempdir()?;
+      let path = dir.path().join("darwin-rebuild.1");
+      generate_man(Some(&path))?;
+      assert!(fs::read_to_string(&path)?.starts_with(".ie"));
+      Ok(())
+    }
+  }
 }
 
 #[cfg(test)]
 mod tests {
   use pretty_assertions::assert_str_eq;
+  use rstest::rstest;
 
   use super::*;
-  use crate::{cli::CompletionArgs, runner::runnable::Runnable};
 
   #[test_log::test]
   fn test_parse_profile_without_profile() -> color_eyre::Result<()> {
     let profile = None;
-    let result = NixDarwinRunner::parse_profile(&profile)?;
+    let result = NixDarwinRunner::parse_profile(&profile, None)?;
     assert_str_eq!(result, DEFAULT_PROFILE);
     Ok(())
   }
@@ -241,7 +905,7 @@ mod tests {
   #[test_log::test]
   fn test_parse_profile_with_system() -> color_eyre::Result<()> {
     let profile = Some("system".to_string());
-    let result = NixDarwinRunner::parse_profile(&profile)?;
+    let result = NixDarwinRunner::parse_profile(&profile, None)?;
     assert_str_eq!(result, DEFAULT_PROFILE);
     Ok(())
   }
@@ -251,8 +915,571 @@ mod tests {
   fn test_parse_profile_with_other() {
     let profile = "other".to_string();
     let profile_opt = Some(profile.clone());
-    let result = NixDarwinRunner::parse_profile(&profile_opt).unwrap();
+    let result = NixDarwinRunner::parse_profile(&profile_opt, None).unwrap();
     assert_str_eq!(result, format!("/nix/var/nix/profiles/system-profiles/{}", profile));
   }
 
+  #[rstest]
+  #[case::work("work", true)]
+  #[case::dotted("work.old", true)]
+  #[case::parent("../escape", false)]
+  #[case::nested("a/b", false)]
+  #[case::absolute("/etc", false)]
+  #[case::dot_dot("..", false)]
+  #[case::empty("", false)]
+  fn test_validate_profile_name(#[case] name: &str, #[case] valid: bool) {
+    assert_eq!(NixDarwinRunner::validate_profile_name(name).is_ok(), valid);
+  }
+
+  #[test_log::test]
+  fn test_parse_profile_rejects_path_traversal() {
+    let err = NixDarwinRunner::parse_profile(&Some("../escape".to_string()), None).unwrap_err();
+    assert_eq!(err.to_string(), format!("invalid profile name {}", "../escape".yellow()));
+  }
+
+  mod extra_flags {
+    use clap::Parser;
+    use pretty_assertions::assert_eq;
+    use rstest::rstest;
+
+    use super::*;
+
+    const APP_NAME: &str = env!("CARGO_BIN_NAME");
+
+    fn parse(args: &[&str]) -> Cli { Cli::parse_from([&[APP_NAME, "build"], args].concat()) }
+
+    #[rstest]
+    #[case::unset(&[], &[])]
+    #[case::substitute(&["--substitute"], &["--substitute"])]
+    #[case::no_substitute(&["--no-substitute"], &["--no-substitute"])]
+    fn test_substitute_is_forwarded(#[case] args: &[&str], #[case] expected: &[&str]) {
+      let cli = parse(args);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), expected);
+      assert_eq!(NixDarwinRunner::parse_extra_metadata_flags(&cli), expected);
+    }
+
+    #[test_log::test]
+    fn test_extra_substituters_are_forwarded() {
+      let cli = parse(&[
+        "--extra-substituters",
+        "https://a.cachix.org",
+        "--extra-trusted-public-keys",
+        "a.cachix.org-1:abc=",
+        "--extra-substituters",
+        "https://b.cachix.org",
+        "--extra-trusted-public-keys",
+        "b.cachix.org-1:def=",
+      ]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), [
+        "--extra-substituters",
+        "https://a.cachix.org",
+        "--extra-substituters",
+        "https://b.cachix.org",
+        "--extra-trusted-public-keys",
+        "a.cachix.org-1:abc=",
+        "--extra-trusted-public-keys",
+        "b.cachix.org-1:def=",
+      ]);
+    }
+
+    #[test_log::test]
+    fn test_trusted_substituters_are_forwarded() {
+      let cli = parse(&["--trusted-substituters", "https://a.example.org", "--trusted-substituters", "s3://b"]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), [
+        "--trusted-substituters",
+        "https://a.example.org s3://b"
+      ]);
+    }
+
+    #[test_log::test]
+    fn test_builders_is_forwarded() {
+      let cli = parse(&["--builders", "ssh://builder x86_64-linux"]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["--builders", "ssh://builder x86_64-linux"]);
+      assert!(NixDarwinRunner::parse_extra_metadata_flags(&cli).is_empty());
+    }
+
+    #[test_log::test]
+    fn test_system_is_forwarded() {
+      let cli = parse(&["--system", "aarch64-darwin"]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["--system", "aarch64-darwin"]);
+    }
+
+    #[rstest]
+    #[case::sandbox("--sandbox", "true")]
+    #[case::no_sandbox("--no-sandbox", "false")]
+    fn test_sandbox_is_forwarded(#[case] flag: &str, #[case] value: &str) {
+      let cli = parse(&[flag]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["--option", "sandbox", value]);
+      assert!(NixDarwinRunner::parse_extra_metadata_flags(&cli).is_empty());
+    }
+
+    #[test_log::test]
+    fn test_system_features_is_forwarded() {
+      let cli = parse(&["--system-features", "big-parallel benchmark"]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["--system-features", "big-parallel benchmark"]);
+      assert!(NixDarwinRunner::parse_extra_metadata_flags(&cli).is_empty());
+    }
+
+    #[rstest]
+    #[case::zero("0")]
+    #[case::one("1")]
+    #[case::large("4294967295")]
+    fn test_max_jobs_and_cores_are_forwarded_verbatim(#[case] value: &str) {
+      let cli = parse(&["--max-jobs", value, "--cores", value]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["--max-jobs", value, "--cores", value]);
+    }
+
+    #[test_log::test]
+    fn test_build_timeouts_are_forwarded() {
+      let cli = parse(&["--build-timeout", "3600", "--max-silent-time", "600"]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["--timeout", "3600", "--max-silent-time", "600"]);
+      assert!(NixDarwinRunner::parse_extra_metadata_flags(&cli).is_empty());
+    }
+
+    #[test_log::test]
+    fn test_verbose_nix_is_forwarded() {
+      let cli = parse(&["--verbose-nix", "3"]);
+      assert!(!cli.verbose);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["-v", "-v", "-v"]);
+    }
+
+    #[test_log::test]
+    fn test_offline_is_forwarded() {
+      let cli = parse(&["--offline"]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["--offline"]);
+      assert_eq!(NixDarwinRunner::parse_extra_metadata_flags(&cli), ["--offline"]);
+    }
+
+    #[test_log::test]
+    fn test_accept_flake_config_is_forwarded() {
+      let cli = parse(&["--accept-flake-config"]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["--accept-flake-config"]);
+      assert_eq!(NixDarwinRunner::parse_extra_metadata_flags(&cli), ["--accept-flake-config"]);
+    }
+
+    #[rstest]
+    #[case::pure_eval("--pure-eval")]
+    #[case::impure("--impure")]
+    fn test_eval_mode_is_forwarded(#[case] flag: &str) {
+      let cli = parse(&[flag]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), [flag]);
+      assert_eq!(NixDarwinRunner::parse_extra_metadata_flags(&cli), [flag]);
+    }
+
+    #[test_log::test]
+    fn test_inputs_from_is_forwarded() {
+      let cli = parse(&["--inputs-from", "github:owner/dotfiles"]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["--inputs-from", "github:owner/dotfiles"]);
+      assert_eq!(NixDarwinRunner::parse_extra_metadata_flags(&cli), ["--inputs-from", "github:owner/dotfiles"]);
+    }
+
+    #[test_log::test]
+    fn test_flake_registry_is_forwarded() {
+      let cli = parse(&["--flake-registry", "/etc/nix/registry.json"]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["--flake-registry", "/etc/nix/registry.json"]);
+      assert_eq!(NixDarwinRunner::parse_extra_metadata_flags(&cli), ["--flake-registry", "/etc/nix/registry.json"]);
+    }
+
+    #[test_log::test]
+    fn test_allow_dirty_is_forwarded() {
+      let cli = parse(&["--allow-dirty"]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["--option", "warn-dirty", "false"]);
+      assert_eq!(NixDarwinRunner::parse_extra_metadata_flags(&cli), ["--option", "warn-dirty", "false"]);
+    }
+
+    #[rstest]
+    #[case::eval_cache("--eval-cache", "true")]
+    #[case::no_eval_cache("--no-eval-cache", "false")]
+    fn test_eval_cache_is_forwarded(#[case] arg: &str, #[case] value: &str) {
+      let cli = parse(&[arg]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["--option", "eval-cache", value]);
+      assert_eq!(NixDarwinRunner::parse_extra_metadata_flags(&cli), ["--option", "eval-cache", value]);
+    }
+
+    #[test_log::test]
+    fn test_refresh_is_forwarded() {
+      let cli = parse(&["--refresh"]);
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), ["--refresh"]);
+      assert_eq!(NixDarwinRunner::parse_extra_metadata_flags(&cli), ["--refresh"]);
+    }
+
+    #[test_log::test]
+    fn test_stores_are_forwarded() {
+      let cli = parse(&["--store", "ssh-ng://builder", "--eval-store", "auto"]);
+      let expected = ["--store", "ssh-ng://builder", "--eval-store", "auto"];
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), expected);
+      assert_eq!(NixDarwinRunner::parse_extra_metadata_flags(&cli), expected);
+    }
+
+    #[test_log::test]
+    fn test_override_flake_is_forwarded() {
+      let cli = parse(&["--override-flake", "nixpkgs", "github#nixos", "--override-flake", "darwin", "/src/darwin"]);
+      let expected = ["--override-flake", "nixpkgs", "github#nixos", "--override-flake", "darwin", "/src/darwin"];
+      assert_eq!(NixDarwinRunner::parse_extra_build_flags(&cli), expected);
+      assert_eq!(NixDarwinRunner::parse_extra_metadata_flags(&cli), expected);
+    }
+
+    #[test_log::test]
+    fn test_arg_json_is_forwarded() {
+      let cli = parse(&["--arg-json", "users", r#"["alice","${bob}"]"#, "--arg-json", "debug", "true"]);
+      assert_eq!(NixDarwinRunner::parse_arg_json(&cli.arg_json).unwrap(), [
+        "--arg",
+        "users",
+        r#"(builtins.fromJSON "[\"alice\",\"\${bob}\"]")"#,
+        "--arg",
+        "debug",
+        r#"(builtins.fromJSON "true")"#,
+      ]);
+    }
+
+    #[test_log::test]
+    fn test_arg_from_file_is_forwarded() -> color_eyre::Result<()> {
+      let dir = tempfile::tempdir()?;
+      let file = dir.path().join("users.nix");
+      std::fs::write(&file, "[ \"alice\" \"bob\" ]\n")?;
+      let cli = parse(&["--arg-from-file", "users", file.to_str().unwrap()]);
+      assert_eq!(NixDarwinRunner::parse_arg_from_file(&cli.arg_from_file)?, ["--arg", "users", r#"[ "alice" "bob" ]"#]);
+      assert!(NixDarwinRunner::new(&cli)?.extra_build_flags.ends_with(&[
+        "--arg".to_string(),
+        "users".to_string(),
+        r#"[ "alice" "bob" ]"#.to_string()
+      ]));
+      Ok(())
+    }
+
+    #[test_log::test]
+    fn test_missing_arg_from_file_is_rejected() {
+      let cli = parse(&["--arg-from-file", "users", "/nonexistent/users.nix"]);
+      let err = NixDarwinRunner::parse_arg_from_file(&cli.arg_from_file).unwrap_err();
+      assert!(err.to_string().contains("/nonexistent/users.nix"), "{:?}", err);
+    }
+
+    #[test_log::test]
+    fn test_invalid_arg_json_is_rejected() {
+      let cli = parse(&["--arg-json", "users", "[alice"]);
+      let err = NixDarwinRunner::parse_arg_json(&cli.arg_json).unwrap_err();
+      assert!(err.to_string().contains("users"), "{:?}", err);
+      assert!(NixDarwinRunner::new(&cli).is_err());
+    }
+  }
+
+  mod canonicalize_flake_path {
+    use pretty_assertions::assert_str_eq;
+    use rstest::rstest;
+
+    use super::*;
+
+    fn assets_path() -> String { nix_commands::get_real_path(&"assets").unwrap() }
+
+    #[rstest]
+    #[case::dot("", "./assets")]
+    #[case::path("path:", "./assets")]
+    #[case::git_file("git+file:", "./assets")]
+    fn test_canonicalize_relative_path(#[case] scheme: &str, #[case] path: &str) -> color_eyre::Result<()> {
+      let result = NixDarwinRunner::canonicalize_flake_path(scheme, "", path)?;
+      assert_str_eq!(result, assets_path());
+      Ok(())
+    }
+
+    #[test_log::test]
+    fn test_canonicalize_parent_path() -> color_eyre::Result<()> {
+      let current_dir = env::current_dir()?;
+      let name = current_dir.file_name().and_then(|name| name.to_str()).unwrap();
+      let result = NixDarwinRunner::canonicalize_flake_path("", "", &format!("../{}/assets", name))?;
+      assert_str_eq!(result, assets_path());
+      Ok(())
+    }
+
+    #[rstest]
+    #[case::registry("", "", "nixpkgs")]
+    #[case::absolute("path:", "", "/etc/nix-darwin")]
+    #[case::github("github:", "", "lnl7/nix-darwin")]
+    #[case::authority("git+file:", "//", "/etc/nix-darwin")]
+    fn test_keep_non_local_path(#[case] scheme: &str, #[case] authority: &str, #[case] path: &str) {
+      let result = NixDarwinRunner::canonicalize_flake_path(scheme, authority, path).unwrap();
+      assert_str_eq!(result, path);
+    }
+
+    #[rstest]
+    #[case::relative("./assets")]
+    #[case::absolute(&assets_path())]
+    fn test_normalize_directory_to_path_ref(#[case] path: &str) -> color_eyre::Result<()> {
+      let result = NixDarwinRunner::normalize_flake_ref("", "", path)?;
+      assert_str_eq!(result, format!("path:{}", assets_path()));
+      Ok(())
+    }
+
+    #[rstest]
+    #[case::registry("", "", "nixpkgs", "nixpkgs")]
+    #[case::github("github:", "", "lnl7/nix-darwin", "github:lnl7/nix-darwin")]
+    fn test_normalize_keeps_non_local_ref(
+      #[case] scheme: &str, #[case] authority: &str, #[case] path: &str, #[case] expected: &str,
+    ) -> color_eyre::Result<()> {
+      assert_str_eq!(NixDarwinRunner::normalize_flake_ref(scheme, authority, path)?, expected);
+      Ok(())
+    }
+  }
+
+  #[rstest]
+  #[case::installed(&[], true, Ok(true))]
+  #[case::fallback(&[], false, Ok(false))]
+  #[case::strict_installed(&["--strict-nom"], true, Ok(true))]
+  #[case::strict_missing(&["--strict-nom"], false, Err("nom is not installed or not in $PATH"))]
+  #[case::disabled(&["--no-nom"], false, Ok(false))]
+  fn test_use_nom(
+    #[case] args: &[&str], #[case] installed: bool, #[case] expected: Result<bool, &str>,
+  ) -> color_eyre::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    use clap::Parser;
+
+    let dir = tempfile::tempdir()?;
+    if installed {
+      let nom = dir.path().join("nom");
+      std::fs::write(&nom, "#!/bin/sh\n")?;
+      std::fs::set_permissions(&nom, std::fs::Permissions::from_mode(0o755))?;
+    }
+    let cli = Cli::parse_from([&[env!("CARGO_BIN_NAME"), "build"], args].concat());
+    let runner = NixDarwinRunner::new(&cli)?;
+    let result = runner.use_nom(Some(dir.path().as_os_str())).map_err(|err| err.to_string());
+    assert_eq!(result, expected.map_err(str::to_string));
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_print_diff_without_nvd_is_not_diffed() -> color_eyre::Result<()> {
+    let cli = Cli { action: Some(Action::Build), no_flake: true, no_nvd: true, ..Default::default() };
+    let runner = NixDarwinRunner::new(&cli)?;
+    assert!(!runner.print_diff("/nix/store/x-darwin-system")?);
+    Ok(())
+  }
+
+  #[rstest]
+  #[case::user(true, "/nix/store/x-darwin-system/activate")]
+  #[case::sudo(false, "sudo '--preserve-env=HOMEBREW_NO_AUTO_UPDATE' /nix/store/x-darwin-system/activate")]
+  fn test_dry_activation_cmds(#[case] is_root: bool, #[case] system_cmd: &str) -> color_eyre::Result<()> {
+    let cli = Cli {
+      action: Some(Action::Activate),
+      no_flake: true,
+      dry_activate: true,
+      force: true,
+      keep_env_vars: vec!["HOMEBREW_NO_AUTO_UPDATE".to_string()],
+      ..Default::default()
+    };
+    let runner = NixDarwinRunner::new(&cli)?;
+    assert_eq!(runner.dry_activation_cmds("/nix/store/x-darwin-system", is_root), [
+      "/nix/store/x-darwin-system/activate-user",
+      system_cmd
+    ]);
+    Ok(())
+  }
+
+  #[rstest]
+  #[case::user(false, "nix-env -p /nix/var/nix/profiles/system --delete-generations 30d")]
+  #[case::sudo(true, "sudo nix-env -p /nix/var/nix/profiles/system --delete-generations 30d")]
+  fn test_keep_since_delete_generations_cmd(#[case] sudo: bool, #[case] expected: &str) -> color_eyre::Result<()> {
+    use clap::Parser;
+
+    let cli = Cli::parse_from([env!("CARGO_BIN_NAME"), "switch", "--keep-since", "30d"]);
+    let runner = NixDarwinRunner::new(&cli)?;
+    let age = runner.keep_since.as_deref().unwrap_or_default();
+    assert_str_eq!(runner.profile_cmd(&["--delete-generations", age], sudo).to_cmdline_lossy(), expected);
+    Ok(())
+  }
+
+  #[rstest]
+  #[case::user(false, "nix-env -p /nix/var/nix/profiles/system --delete-generations 1 2")]
+  #[case::sudo(
+    true,
+    "SUDO_ASKPASS=/usr/local/bin/askpass sudo -A nix-env -p /nix/var/nix/profiles/system --delete-generations 1 2"
+  )]
+  fn test_keep_delete_generations_cmd(#[case] sudo: bool, #[case] expected: &str) -> color_eyre::Result<()> {
+    use clap::Parser;
+
+    let cli =
+      Cli::parse_from([env!("CARGO_BIN_NAME"), "switch", "--keep", "3", "--sudo-askpass", "/usr/local/bin/askpass"]);
+    let runner = NixDarwinRunner::new(&cli)?;
+    let flags = NixDarwinRunner::delete_generations_flags(&[1, 2]);
+    assert_str_eq!(runner.profile_cmd(&flags, sudo).to_cmdline_lossy(), expected);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_activation_system_config_uses_override() -> color_eyre::Result<()> {
+    use clap::Parser;
+
+    let cli = Cli::parse_from([env!("CARGO_BIN_NAME"), "activate", "--system-config", "/nix/store/x-darwin-system"]);
+    let runner = NixDarwinRunner::new(&cli)?;
+    assert_str_eq!(runner.activation_system_config()?, "/nix/store/x-darwin-system");
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_activation_system_config_reads_profile() -> color_eyre::Result<()> {
+    use clap::Parser;
+
+    let dir = tempfile::tempdir()?;
+    std::fs::write(dir.path().join("systemConfig"), "/nix/store/x-darwin-system")?;
+    let cli = Cli::parse_from([env!("CARGO_BIN_NAME"), "activate"]);
+    let mut runner = NixDarwinRunner::new(&cli)?;
+    runner.profile = dir.path().display().to_string();
+    assert_str_eq!(runner.activation_system_config()?, "/nix/store/x-darwin-system");
+    Ok(())
+  }
+
+  #[rstest]
+  #[case::same_system("system-1", &[], false)]
+  #[case::other_system("system-2", &[], true)]
+  #[case::forced_same_system("system-1", &["--force"], true)]
+  fn test_should_activate_system(
+    #[case] target: &str, #[case] args: &[&str], #[case] expected: bool,
+  ) -> color_eyre::Result<()> {
+    use clap::Parser;
+
+    let dir = tempfile::tempdir()?;
+    std::fs::create_dir(dir.path().join("system-1"))?;
+    std::fs::create_dir(dir.path().join("system-2"))?;
+    let current_system = dir.path().join("current-system");
+    std::os::unix::fs::symlink(dir.path().join("system-1"), &current_system)?;
+
+    let cli = Cli::parse_from([&[env!("CARGO_BIN_NAME"), "activate"], args].concat());
+    let runner = NixDarwinRunner::new(&cli)?;
+    let target = dir.path().join(target);
+    assert_eq!(runner.should_activate_system(current_system.to_str().unwrap(), target.to_str().unwrap()), expected);
+    Ok(())
+  }
+
+  #[rstest]
+  #[case::first_switch("", "   1   2024-06-01 10:12:45   (current)\n", "switched to generation 1")]
+  #[case::switch(
+    "   1   2024-06-01 10:12:45   (current)\n",
+    "   1   2024-06-01 10:12:45\n   2   2024-06-12 18:03:11   (current)\n",
+    "switched from generation 1 to 2"
+  )]
+  fn test_generation_transition(#[case] before: &str, #[case] after: &str, #[case] expected: &str) {
+    let old_generation = nix_commands::parse_current_generation(before);
+    let new_generation = nix_commands::parse_current_generation(after).unwrap();
+    assert_str_eq!(NixDarwinRunner::generation_transition(old_generation, new_generation), expected);
+  }
+
+  #[test_log::test]
+  fn test_no_flake_ignores_flake_env() -> color_eyre::Result<()> {
+    // `$FLAKE` is read by clap into `flake`
+    let args = Cli { flake: Some("github:owner/dotfiles#host".to_string()), no_flake: true, ..Default::default() };
+    let result = NixDarwinRunner::parse_flake(&args, args.flake.as_deref(), &[], &[])?;
+    assert_eq!(result, (None, vec![]));
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_parse_flake_uses_cached_metadata() -> color_eyre::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("flake-metadata.json");
+    let mut cache = MetadataCache::load(&path, Duration::from_secs(60));
+    cache.insert("github:owner/dotfiles", serde_json::json!({ "url": "github:owner/dotfiles/abc" }), SystemTime::now());
+    cache.save()?;
+
+    let args = Cli { cache_flake_metadata: Some(path), cache_flake_metadata_ttl: 60, ..Default::default() };
+    let result = NixDarwinRunner::parse_flake(&args, Some("github:owner/dotfiles#host"), &[], &[])?;
+    assert_eq!(result, (Some("github:owner/dotfiles/abc".to_string()), vec!["host".to_string()]));
+    Ok(())
+  }
+
+  #[rstest]
+  #[case::dirty(false, true)]
+  #[case::allowed(true, false)]
+  fn test_dirty_warning(#[case] allow_dirty: bool, #[case] warns: bool) {
+    let metadata = serde_json::json!({ "url": "git+file:///Users/me/dotfiles", "dirtyRevision": "abc123-dirty" });
+    let warning = NixDarwinRunner::dirty_warning(&metadata, allow_dirty);
+    assert_eq!(warning.is_some(), warns, "{warning:?}");
+  }
+
+  #[test_log::test]
+  fn test_dirty_warning_on_clean_tree() {
+    let metadata = serde_json::json!({ "url": "git+file:///Users/me/dotfiles", "revision": "abc123" });
+    assert_eq!(NixDarwinRunner::dirty_warning(&metadata, false), None);
+  }
+
+  #[rstest]
+  #[case::same("github:owner/dotfiles", "github:owner/dotfiles", false)]
+  #[case::query("path:/etc/nix-darwin", "path:/etc/nix-darwin?lastModified=1&narHash=sha256-x", false)]
+  #[case::trailing_slash("path:/etc/nix-darwin/", "path:/etc/nix-darwin", false)]
+  #[case::git("path:/Users/me/dotfiles", "git+file:///Users/me/dotfiles", true)]
+  #[case::registry("nixpkgs", "github:NixOS/nixpkgs/nixpkgs-unstable", true)]
+  fn test_is_flake_rewritten(#[case] input: &str, #[case] resolved: &str, #[case] expected: bool) {
+    assert_eq!(NixDarwinRunner::is_flake_rewritten(input, resolved), expected);
+  }
+
+  #[rstest]
+  #[case::one(serde_json::json!(1), true)]
+  #[case::zero(serde_json::json!(0), false)]
+  #[case::string_one(serde_json::json!("1"), true)]
+  #[case::string_zero(serde_json::json!("0"), false)]
+  #[case::string_true(serde_json::json!("true"), true)]
+  #[case::bool_true(serde_json::json!(true), true)]
+  #[case::bool_false(serde_json::json!(false), false)]
+  #[case::null(serde_json::Value::Null, false)]
+  #[test_log::test]
+  fn test_parse_submodules(#[case] value: serde_json::Value, #[case] expected: bool) -> color_eyre::Result<()> {
+    assert_eq!(NixDarwinRunner::parse_submodules(&value)?, expected);
+    Ok(())
+  }
+
+  #[rstest]
+  #[case::number(serde_json::json!(2))]
+  #[case::string(serde_json::json!("yes"))]
+  #[case::array(serde_json::json!([true]))]
+  #[test_log::test]
+  fn test_parse_submodules_rejects_non_booleans(#[case] value: serde_json::Value) {
+    let err = NixDarwinRunner::parse_submodules(&value).unwrap_err();
+    assert!(err.to_string().contains("submodules is not a boolean"), "{err}");
+  }
+
+  #[test_log::test]
+  fn test_parse_hostnames_prefers_explicit_hostnames() -> color_eyre::Result<()> {
+    let hostnames = vec!["hostA".to_string(), "hostB".to_string()];
+    let result = NixDarwinRunner::parse_hostnames(&hostnames, Some("attr"), false)?;
+    assert_eq!(result, hostnames);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_parse_hostnames_uses_flake_attr() -> color_eyre::Result<()> {
+    let result = NixDarwinRunner::parse_hostnames(&[], Some("attr"), false)?;
+    assert_eq!(result, vec!["attr".to_string()]);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_parse_hostnames_falls_back_to_local_hostname() -> color_eyre::Result<()> {
+    let result = NixDarwinRunner::parse_hostnames(&[], None, false)?;
+    assert_eq!(result, vec![nix_commands::get_local_hostname(false)?]);
+    let result = NixDarwinRunner::parse_hostnames(&[], None, true)?;
+    assert_eq!(result, vec![nix_commands::get_local_hostname(true)?]);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_resolve_profile_defaults_without_env() {
+    let result = NixDarwinRunner::resolve_profile(None, None);
+    assert_str_eq!(result, DEFAULT_PROFILE);
+  }
+
+  #[test_log::test]
+  fn test_resolve_profile_uses_env_without_profile_name() {
+    let result = NixDarwinRunner::resolve_profile(None, Some("/tmp/profile".to_string()));
+    assert_str_eq!(result, "/tmp/profile");
+  }
+
+  #[test_log::test]
+  fn test_resolve_profile_system_wins_over_env() {
+    let result = NixDarwinRunner::resolve_profile(Some("system"), Some("/tmp/profile".to_string()));
+    assert_str_eq!(result, DEFAULT_PROFILE);
+  }
+
+  #[test_log::test]
+  fn test_resolve_profile_other_wins_over_env() {
+    let result = NixDarwinRunner::resolve_profile(Some("other"), Some("/tmp/profile".to_string()));
+    assert_str_eq!(result, "/nix/var/nix/profiles/system-profiles/other");
+  }
 }
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Machine-readable outcome of a run, printed with `--json`
+#[derive(Debug, Serialize)]
+pub(super) struct RunSummary {
+  pub action: String,
+  pub success: bool,
+  pub out_path: Option<String>,
+  pub old_generation: Option<u32>,
+  pub new_generation: Option<u32>,
+  pub elapsed_seconds: f64,
+}
+
+impl RunSummary {
+  pub fn new(
+    action: &impl std::fmt::Debug, result: &color_eyre::Result<Option<String>>, old_generation: Option<u32>,
+    new_generation: Option<u32>, elapsed: Duration,
+  ) -> Self {
+    Self {
+      action: format!("{:?}", action).to_lowercase(),
+      success: result.is_ok(),
+      out_path: result.as_ref().ok().cloned().flatten(),
+      old_generation,
+      new_generation,
+      elapsed_seconds: elapsed.as_secs_f64(),
+    }
+  }
+
+  /// One-line outcome of the run, printed with `--summary-only`
+  pub fn line(&self) -> String {
+    let outcome = if self.success { "succeeded" } else { "failed" };
+    let mut line = format!("{} {} in {:.1}s", self.action, outcome, self.elapsed_seconds);
+    if let (Some(old), Some(new)) = (self.old_generation, self.new_generation) {
+      if old != new {
+        line.push_str(&format!(", generation {} -> {}", old, new));
+      }
+    }
+    line
+  }
+}
+
+/// Outcome of a run with the configuration it applied to, written with `--report`
+#[derive(Debug, Serialize)]
+pub(super) struct RunReport<'a> {
+  pub hosts: &'a [String],
+  pub flake: Option<&'a str>,
+  #[serde(flatten)]
+  pub summary: RunSummary,
+  pub error: Option<String>,
+}
+
+impl<'a> RunReport<'a> {
+  pub fn new(
+    hosts: &'a [String], flake: Option<&'a str>, summary: RunSummary, result: &color_eyre::Result<Option<String>>,
+  ) -> Self {
+    Self { hosts, flake, summary, error: result.as_ref().err().map(|err| err.to_string()) }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_eq;
+  use rstest::rstest;
+
+  use super::*;
+  use crate::runner::nix_darwin_action::NixDarwinAction;
+
+  #[test_log::test]
+  fn test_successful_build_summary() -> color_eyre::Result<()> {
+    let result = Ok(Some("/nix/store/x-darwin-system".to_string()));
+    let summary = RunSummary::new(&NixDarwinAction::Build, &result, Some(3), Some(3), Duration::from_millis(1500));
+    let json = serde_json::to_value(&summary)?;
+    let keys: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+    assert_eq!(keys, ["action", "elapsed_seconds", "new_generation", "old_generation", "out_path", "success"]);
+    assert_eq!(json["action"], "build");
+    assert_eq!(json["success"], true);
+    assert_eq!(json["out_path"], "/nix/store/x-darwin-system");
+    assert_eq!(json["elapsed_seconds"], 1.5);
+    Ok(())
+  }
+
+  #[rstest]
+  #[case::switch(
+    NixDarwinAction::Switch,
+    Ok(None),
+    Some(41),
+    Some(42),
+    "switch succeeded in 12.3s, generation 41 -> 42"
+  )]
+  #[case::build(NixDarwinAction::Build, Ok(None), Some(41), Some(41), "build succeeded in 12.3s")]
+  #[case::failed(NixDarwinAction::Switch, Err(color_eyre::eyre::eyre!("failed")), Some(41), None, "switch failed in 12.3s")]
+  #[test_log::test]
+  fn test_summary_line(
+    #[case] action: NixDarwinAction, #[case] result: color_eyre::Result<Option<String>>, #[case] old: Option<u32>,
+    #[case] new: Option<u32>, #[case] expected: &str,
+  ) {
+    let summary = RunSummary::new(&action, &result, old, new, Duration::from_millis(12_300));
+    assert_eq!(summary.line(), expected);
+  }
+
+  #[test_log::test]
+  fn test_failed_switch_report() -> color_eyre::Result<()> {
+    let result = Err(color_eyre::eyre::eyre!("Failed to build the system configuration"));
+    let summary = RunSummary::new(&NixDarwinAction::Switch, &result, Some(3), None, Duration::ZERO);
+    let hosts = ["work-laptop".to_string()];
+    let report = RunReport::new(&hosts, Some("github:owner/dotfiles"), summary, &result);
+    let json = serde_json::to_value(&report)?;
+    assert_eq!(json["hosts"], serde_json::json!(["work-laptop"]));
+    assert_eq!(json["flake"], "github:owner/dotfiles");
+    assert_eq!(json["action"], "switch");
+    assert_eq!(json["success"], false);
+    assert_eq!(json["error"], "Failed to build the system configuration");
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_failed_switch_summary() -> color_eyre::Result<()> {
+    let result = Err(color_eyre::eyre::eyre!("Failed to build the system configuration"));
+    let summary = RunSummary::new(&NixDarwinAction::Switch, &result, Some(3), None, Duration::ZERO);
+    let json = serde_json::to_value(&summary)?;
+    assert_eq!(json["action"], "switch");
+    assert_eq!(json["success"], false);
+    assert_eq!(json["out_path"], serde_json::Value::Null);
+    assert_eq!(json["new_generation"], serde_json::Value::Null);
+    Ok(())
+  }
+}
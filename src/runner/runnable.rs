@@ -1,15 +1,21 @@
-use std::{env, env::args};
+use std::{
+  env, fs,
+  io::{IsTerminal, Write},
+  path::Path,
+  time::{Duration, Instant},
+};
 
-use color_eyre::{eyre::bail, owo_colors::OwoColorize};
-use log::{debug, info};
+use color_eyre::{eyre::eyre, owo_colors::OwoColorize, Section};
+use log::{debug, info, warn};
 
 use crate::{
-  nix_commands,
+  init, nix_commands,
   runner::{
     nix_darwin_action::NixDarwinAction,
-    nix_darwin_runner::{completion::generate_completion, NixDarwinRunner},
+    nix_darwin_runner::{completion::generate_completion, man::generate_man, NixDarwinRunner, SwitchSteps},
+    run_summary::{RunReport, RunSummary},
   },
-  DEFAULT_PROFILE,
+  state, DEFAULT_PROFILE, SYSTEM_PROFILES,
 };
 
 pub trait Runnable {
@@ -18,80 +24,310 @@ pub trait Runnable {
 
 impl Runnable for NixDarwinRunner {
   fn run(&self) -> color_eyre::Result<()> {
-    let out_dir = tempfile::Builder::new().prefix("nix-darwin-").tempdir()?;
-    let out_link = out_dir.path().join("result");
-    let out_link_str = out_link.clone().to_str().unwrap().to_string();
+    if self.print_flake_attr {
+      return self.write_flake_attr(&mut std::io::stdout());
+    }
+
+    let out_dir = if self.no_link { None } else { Some(tempfile::Builder::new().prefix("nix-darwin-").tempdir()?) };
+    let out_link = out_dir.as_ref().map(|out_dir| out_dir.path().join("result"));
+    let out_link_str = out_link.as_ref().map(|out_link| out_link.to_str().unwrap().to_string());
     debug!("out_dir: {:?}", out_dir.black().on_yellow());
     debug!("out_link: {:?}", out_link.yellow());
 
     #[cfg(debug_assertions)]
-    {
-      let exists = std::fs::exists(&out_link_str)?;
+    if let Some(out_link) = &out_link_str {
+      let exists = std::fs::exists(out_link)?;
       debug_assert!(!exists, "the system configuration should not exist");
     }
 
-    let action = if let Some(action) = self.action {
+    let action = if let Some(action) = self.action.clone() {
       action.into()
     } else if self.rollback {
       NixDarwinAction::Rollback
     } else if self.list_generations {
       NixDarwinAction::ListGenerations
     } else {
-      bail!("No action specified")
+      return Err(eyre!("No action specified"));
     };
 
     info!("Starting action: {:?}", action.bold().purple());
-    let result = match action {
+    let summarize = (self.json || self.summary_only) && action.has_summary();
+    let record = summarize || self.report.is_some();
+    let start = Instant::now();
+    let old_generation = if record { nix_commands::current_generation(&self.profile).ok() } else { None };
+    let result = tracing::info_span!("run_action", action = ?action)
+      .in_scope(|| self.run_action(&action, out_dir.as_ref().map(|out_dir| out_dir.path()), out_link_str.as_deref()));
+    if record {
+      let new_generation = nix_commands::current_generation(&self.profile).ok();
+      let summary = RunSummary::new(&action, &result, old_generation, new_generation, start.elapsed());
+      if summarize && self.json {
+        println!("{}", serde_json::to_string(&summary)?);
+      } else if summarize {
+        println!("{}", summary.line());
+      }
+      if let Some(path) = &self.report {
+        let report = RunReport::new(&self.hostnames, self.flake.as_deref(), summary, &result);
+        match (Self::write_report(path, &report), &result) {
+          (Err(err), Ok(_)) => return Err(err),
+          (Err(err), Err(_)) => warn!("{:?}", err),
+          _ => {},
+        }
+      }
+    }
+    self.ring_bell(&mut std::io::stdout(), start.elapsed(), std::io::stdout().is_terminal())?;
+    drop(out_dir);
+    result.map(|_| ())
+  }
+}
+
+impl NixDarwinRunner {
+  /// Writes what the build action prints to stdout: the out path of the build, followed by a newline, when it is
+  /// captured by scripts, nothing otherwise
+  fn write_build_output(&self, out: &mut impl Write, out_path: &str) -> std::io::Result<()> {
+    if self.fast || self.out_path_only || self.no_link {
+      writeln!(out, "{}", out_path)?;
+    }
+    Ok(())
+  }
+
+  /// Writes the flake attribute of the configuration resolved from the flake and the hostname
+  fn write_flake_attr(&self, out: &mut impl Write) -> color_eyre::Result<()> {
+    if self.flake.is_none() {
+      return Err(eyre!("--print-flake-attr requires a flake"));
+    }
+    writeln!(out, "{}", self.flake_attr)?;
+    Ok(())
+  }
+
+  /// Writes the report of the run as JSON to `path`
+  fn write_report(path: &Path, report: &RunReport) -> color_eyre::Result<()> {
+    debug!("Writing the run report to {:?}", path);
+    fs::write(path, serde_json::to_string_pretty(report)?)
+      .map_err(|e| eyre!("unable to write the report to {:?}", path).with_error(|| e))
+  }
+
+  /// Points the profile at `system_config` then activates it, as far as `steps` allow
+  fn run_switch_steps(
+    system_config: &str, steps: SwitchSteps, switch_profile: impl FnOnce(&str) -> color_eyre::Result<()>,
+    activate_profile: impl FnOnce(&str) -> color_eyre::Result<()>,
+  ) -> color_eyre::Result<()> {
+    if !steps.set_profile {
+      info!("dry activation, the profile is left unchanged");
+      return activate_profile(system_config);
+    }
+    switch_profile(system_config)?;
+    if steps.activate {
+      activate_profile(system_config)
+    } else {
+      info!("activation deferred, run {} to activate {}", "darwin-rebuild activate".bold(), system_config.yellow());
+      Ok(())
+    }
+  }
+
+  /// Points the profile at `system_config`, returning the new generation when it can be read back
+  fn switch_generation(&self, system_config: &str) -> color_eyre::Result<Option<u32>> {
+    let old_generation = nix_commands::current_generation(&self.profile).ok();
+    self.switch_profile(&system_config)?;
+    match nix_commands::current_generation(&self.profile) {
+      Ok(new_generation) => {
+        info!("{}", Self::generation_transition(old_generation, new_generation));
+        Ok(Some(new_generation))
+      },
+      Err(err) => {
+        debug!("unable to get the new generation: {:?}", err);
+        Ok(None)
+      },
+    }
+  }
+
+  /// Rings the terminal bell with `--bell` when the run took longer than the threshold
+  fn ring_bell(&self, out: &mut impl Write, elapsed: Duration, is_terminal: bool) -> std::io::Result<()> {
+    if self.bell && is_terminal && elapsed >= self.bell_threshold {
+      write!(out, "\x07")?;
+      out.flush()?;
+    }
+    Ok(())
+  }
+
+  /// Runs the action, returning the system configuration it built or activated if any
+  fn run_action(
+    &self, action: &NixDarwinAction, out_dir: Option<&Path>, out_link: Option<&str>,
+  ) -> color_eyre::Result<Option<String>> {
+    if let (NixDarwinAction::Build | NixDarwinAction::Check | NixDarwinAction::Switch, Some(pre_build)) =
+      (action, &self.pre_build)
+    {
+      nix_commands::exec_hook("pre-build", pre_build)?;
+    }
+    match action {
+      NixDarwinAction::Rollback if self.preview => self.preview_rollback().map(|_| None),
       NixDarwinAction::Rollback => {
+        let _lock = self.lock_profile()?;
         let extra_profile_flags = vec!["--rollback"];
         self.run_profile(&extra_profile_flags)?;
-        let system_config = std::fs::read_to_string(format!("{}/systemConfig", self.profile)).unwrap();
-        Self::activate_profile(&system_config)
+        let system_config = nix_commands::profile_system_config(&self.profile)?;
+        self.activate_profile(&system_config).map(|_| Some(system_config))
       },
       NixDarwinAction::ListGenerations => {
+        if std::path::Path::new(&self.profile).symlink_metadata().is_err() {
+          info!("no generations found for {}", self.profile.yellow());
+          return Ok(None);
+        }
         let extra_profile_flags = vec!["--list-generations"];
-        self.run_profile(&extra_profile_flags)
+        self.run_profile(&extra_profile_flags).map(|_| None)
       },
       NixDarwinAction::Edit => {
         let darwin_config = nix_commands::nix_instantiate_find_file("darwin-config")?;
         if let Some(flake) = &self.flake {
-          nix_commands::nix_edit(flake, &self.flake_attr, &self.flake_flags)
+          nix_commands::nix_edit(flake, &self.flake_attr, &self.flake_flags)?;
         } else {
-          nix_commands::exec_editor(&darwin_config)
+          nix_commands::exec_editor(&darwin_config)?;
         }
+        Ok(None)
       },
       NixDarwinAction::Activate => {
-        let path = args().next().unwrap().replace("/sw/bin/darwin-rebuild", "");
-        let system_config = nix_commands::get_real_path(&path)?;
-        Self::activate_profile(&system_config)
+        let system_config = self.activation_system_config()?;
+        if self.dry_activate {
+          self.print_diff(&system_config)?;
+        }
+        self.activate_profile(&system_config).map(|_| Some(system_config))
       },
-      NixDarwinAction::Build => self.build_configuration(&out_link_str).map(|_| ()),
-      NixDarwinAction::Check => {
-        let system_config = self.build_configuration(&out_link_str)?;
-        unsafe {
-          env::set_var("checkActivation", "1");
+      NixDarwinAction::Build if self.hostnames.len() > 1 => {
+        let results = self.build_configurations(out_dir)?;
+        Self::print_build_summary(&results);
+        Ok(None)
+      },
+      NixDarwinAction::Build => {
+        let outcome = self.build_configuration(out_link)?;
+        debug!("build outcome: {:?}", outcome);
+        let out_path = outcome.out_path;
+        let store_path = nix_commands::get_real_path(&out_path)?;
+        self.write_build_output(&mut std::io::stdout(), &store_path)?;
+        // the build succeeded, failing to record it only costs `activate` its default configuration
+        if let Err(err) = state::state_dir().and_then(|dir| state::write_last_build(&dir, &store_path)) {
+          warn!("unable to record the last build: {:?}", err);
         }
-        nix_commands::exec_activate_user(&system_config)
+        self.keep_result(&store_path)?;
+        self.show_closure_size(&store_path)?;
+        self.show_why_depends(&store_path)?;
+        Ok(Some(out_path))
+      },
+      NixDarwinAction::Check => {
+        let system_config = self.build_configuration(out_link)?.out_path;
+        self.show_closure_size(&system_config)?;
+        self.show_why_depends(&system_config)?;
+        nix_commands::exec_activate_user(&system_config, true).map(|_| Some(system_config))
       },
       NixDarwinAction::Switch => {
-        let system_config = self.build_configuration(&out_link_str)?;
+        if let Some(input) = &self.flake_update_input {
+          let Some(flake) = &self.flake else {
+            return Err(eyre!("updating a flake input requires a flake"));
+          };
+          nix_commands::flake_lock_update_input(flake, input, &self.flake_flags)?;
+          if self.commit_lock {
+            match nix_commands::local_flake_dir(flake) {
+              Some(dir) => nix_commands::git_commit_lockfile(dir, &format!("Update flake input {}", input))?,
+              None => warn!("{} is not a local flake, not committing flake.lock", flake.yellow()),
+            }
+          }
+        }
+        let system_config = self.build_configuration(out_link)?.out_path;
         #[cfg(debug_assertions)]
         {
           let exists = std::fs::exists(&system_config)?;
           debug_assert!(exists, "the system configuration does not exist");
         }
+        self.keep_result(&system_config)?;
+        self.show_closure_size(&system_config)?;
+        self.show_why_depends(&system_config)?;
 
-        self.switch_profile(&system_config)?;
-        Self::activate_profile(&system_config)
+        let steps = self.switch_steps();
+        let _lock = if steps.set_profile { Some(self.lock_profile()?) } else { None };
+        let mut new_generation = None;
+        Self::run_switch_steps(
+          &system_config,
+          steps,
+          |system_config| {
+            new_generation = self.switch_generation(system_config)?;
+            Ok(())
+          },
+          |system_config| self.activate_profile(&system_config),
+        )?;
+        if !steps.set_profile {
+          return Ok(Some(system_config));
+        }
+        match (&self.message, new_generation) {
+          (Some(message), Some(generation)) => {
+            let written =
+              state::state_dir().and_then(|dir| state::write_generation_note(&dir, &self.profile, generation, message));
+            if let Err(err) = written {
+              warn!("unable to save the note of generation {}: {:?}", generation, err);
+            }
+          },
+          (Some(_), None) => warn!("unable to find the new generation, its note is not saved"),
+          _ => {},
+        }
+        if let Some(keep) = self.keep {
+          let to_delete = nix_commands::prune_generations(&self.profile, keep)?;
+          if to_delete.is_empty() {
+            info!("no generations to prune");
+          } else {
+            info!("pruning generations {:?}...", to_delete.yellow());
+            self.run_profile(&Self::delete_generations_flags(&to_delete))?;
+          }
+        }
+        if let Some(age) = &self.keep_since {
+          info!("deleting generations older than {}...", age.yellow());
+          self.run_profile(&["--delete-generations", age])?;
+        }
+        if self.keep.is_some() || self.keep_since.is_some() {
+          if let Err(err) = state::state_dir().and_then(|dir| state::remove_stale_notes(&dir, &self.profile)) {
+            warn!("unable to remove the notes of the deleted generations: {:?}", err);
+          }
+        }
+        Ok(Some(system_config))
       },
       NixDarwinAction::Changelog => {
         info!("\nCHANGELOG\n");
-        nix_commands::print_changelog(DEFAULT_PROFILE)
+        nix_commands::print_changelog(DEFAULT_PROFILE).map(|_| None)
       },
-      NixDarwinAction::Completions(shell) => generate_completion(shell),
-    };
-    drop(out_dir);
-    result
+      NixDarwinAction::CurrentGeneration => {
+        let generation = nix_commands::current_generation(&self.profile)?;
+        println!("{}", generation);
+        Ok(None)
+      },
+      NixDarwinAction::Generations => {
+        let generations = self.with_notes(nix_commands::list_generations_detailed(&self.profile)?);
+        if self.json {
+          println!("{}", serde_json::to_string_pretty(&generations)?);
+        } else {
+          print!("{}", nix_commands::render_generations(&generations));
+        }
+        Ok(None)
+      },
+      NixDarwinAction::Profiles => {
+        let profiles = nix_commands::list_system_profiles(Path::new(SYSTEM_PROFILES))?;
+        if self.json {
+          println!("{}", serde_json::to_string_pretty(&profiles)?);
+        } else {
+          print!("{}", nix_commands::render_system_profiles(&profiles));
+        }
+        Ok(None)
+      },
+      NixDarwinAction::Compare(from, to) => {
+        let from = nix_commands::generation_store_path(&self.profile, *from)?;
+        let to = nix_commands::generation_store_path(&self.profile, *to)?;
+        if let Some(summary) = nix_commands::nvd_diff(&from, &to, self.diff_summary)? {
+          info!("{}", summary.bold());
+        }
+        Ok(None)
+      },
+      NixDarwinAction::Init => {
+        let hostname = nix_commands::get_local_hostname(self.fqdn)?;
+        init::write_flake(&env::current_dir()?, &hostname, self.force).map(|_| None)
+      },
+      NixDarwinAction::Completions(shell, output) => generate_completion(*shell, output.as_deref()).map(|_| None),
+      NixDarwinAction::Man(output) => generate_man(output.as_deref()).map(|_| None),
+    }
   }
 }
 
@@ -106,7 +342,10 @@ mod tests {
     use clap::Parser;
 
     use super::*;
-    use crate::{cli::Cli, runner::runnable::NixDarwinRunner};
+    use crate::{
+      cli::Cli,
+      runner::{nix_darwin_runner::SwitchSteps, runnable::NixDarwinRunner},
+    };
 
     const APP_NAME: &str = env!("CARGO_BIN_NAME");
     fn get_runner(args: Vec<&str>) -> NixDarwinRunner {
@@ -146,6 +385,123 @@ mod tests {
       assert!(result.is_ok(), "{:?}", result);
     }
 
+    #[test_log::test]
+    fn should_skip_nom_and_nvd_with_fast() {
+      let runner = get_runner(["build", "--fast"].into());
+      assert!(!runner.nom);
+      assert!(!runner.nvd);
+      let result = runner.print_diff("/nonexistent/result");
+      assert!(result.is_ok(), "{:?}", result);
+    }
+
+    #[rstest]
+    #[case::out_path_only(&["build", "--out-path-only"], "/nix/store/x-darwin-system\n")]
+    #[case::no_link(&["build", "--no-link"], "/nix/store/x-darwin-system\n")]
+    #[case::default(&["build"], "")]
+    fn should_only_print_out_path_with_out_path_only(#[case] args: &[&str], #[case] expected: &str) {
+      let runner = get_runner(args.to_vec());
+      let mut stdout = vec![];
+      runner.write_build_output(&mut stdout, "/nix/store/x-darwin-system").unwrap();
+      assert_eq!(String::from_utf8(stdout).unwrap(), expected);
+    }
+
+    #[test_log::test]
+    fn should_not_run_nom_or_nvd_with_out_path_only() {
+      let runner = get_runner(["build", "--out-path-only"].into());
+      assert!(!runner.nom);
+      assert!(!runner.nvd);
+    }
+
+    #[rstest]
+    #[case::exceeded(&["--bell"], 31, true, true)]
+    #[case::below_threshold(&["--bell"], 29, true, false)]
+    #[case::custom_threshold(&["--bell", "--bell-threshold", "5"], 6, true, true)]
+    #[case::not_a_terminal(&["--bell"], 31, false, false)]
+    #[case::without_flag(&[], 31, true, false)]
+    #[test_log::test]
+    fn should_ring_bell_after_threshold(
+      #[case] args: &[&str], #[case] elapsed: u64, #[case] is_terminal: bool, #[case] rings: bool,
+    ) {
+      let runner = get_runner([&["build"], args].concat());
+      let mut stdout = vec![];
+      runner.ring_bell(&mut stdout, Duration::from_secs(elapsed), is_terminal).unwrap();
+      assert_eq!(stdout == b"\x07", rings, "{stdout:?}");
+    }
+
+    #[test_log::test]
+    fn should_hide_build_logs_with_summary_only() {
+      let runner = get_runner(["switch", "--summary-only"].into());
+      assert!(runner.summary_only);
+      assert!(!runner.nom);
+      assert!(runner.build_logs(runner.nom).quiet);
+      assert!(!get_runner(["switch"].into()).build_logs(false).quiet);
+    }
+
+    #[test_log::test]
+    fn should_not_run_nom_with_json() {
+      let runner = get_runner(["switch", "--json"].into());
+      assert!(runner.json);
+      assert!(!runner.nom);
+    }
+
+    #[test_log::test]
+    fn should_not_run_nom_with_no_link() {
+      let runner = get_runner(["build", "--no-link"].into());
+      assert!(runner.no_link);
+      assert!(!runner.nom);
+    }
+
+    #[test_log::test]
+    fn should_not_run_nvd_with_no_nvd() {
+      let runner = get_runner(["build", "--no-nvd"].into());
+      assert!(!runner.nvd);
+      let result = runner.print_diff("/nonexistent/result");
+      assert!(result.is_ok(), "{:?}", result);
+    }
+
+    #[test_log::test]
+    fn should_not_build_when_pre_build_fails() {
+      let dir = tempfile::tempdir().unwrap();
+      let marker = dir.path().join("marker");
+      let pre_build = format!("touch {} && false", marker.display());
+      let runner = get_runner(["build", "--pre-build", &pre_build].into());
+      let result = runner.run();
+      assert!(marker.exists());
+      let err = result.expect_err("the pre-build hook should abort the build");
+      assert!(err.to_string().contains("pre-build"), "{:?}", err);
+    }
+
+    #[rstest]
+    #[case::default(&["switch"], SwitchSteps { set_profile: true, activate: true })]
+    #[case::no_activate(&["switch", "--no-activate"], SwitchSteps { set_profile: true, activate: false })]
+    #[case::dry_activate(&["switch", "--dry-activate"], SwitchSteps { set_profile: false, activate: true })]
+    fn should_defer_activation_with_no_activate(#[case] args: &[&str], #[case] expected: SwitchSteps) {
+      assert_eq!(get_runner(args.to_vec()).switch_steps(), expected);
+    }
+
+    #[rstest]
+    #[case::default(&["switch"], &["switch_profile", "activate_profile"])]
+    #[case::no_activate(&["switch", "--no-activate"], &["switch_profile"])]
+    #[case::dry_activate(&["switch", "--dry-activate"], &["activate_profile"])]
+    fn should_run_the_switch_steps(#[case] args: &[&str], #[case] expected: &[&str]) {
+      let steps = get_runner(args.to_vec()).switch_steps();
+      let calls = std::cell::RefCell::new(vec![]);
+      let result = NixDarwinRunner::run_switch_steps(
+        "/nix/store/x-darwin-system",
+        steps,
+        |_| {
+          calls.borrow_mut().push("switch_profile");
+          Ok(())
+        },
+        |_| {
+          calls.borrow_mut().push("activate_profile");
+          Ok(())
+        },
+      );
+      assert!(result.is_ok(), "{:?}", result);
+      assert_eq!(calls.into_inner(), expected);
+    }
+
     #[test_log::test]
     #[ignore]
     fn should_run_check() {
@@ -213,6 +569,15 @@ mod tests {
       assert!(result.is_ok(), "Result: {:?}", result.red());
     }
 
+    #[test_log::test]
+    fn should_run_build_multiple_hostnames() {
+      let runner =
+        get_runner(["build", "--hostname", "darwin-rebuild-rs", "--hostname", "darwin-rebuild-rs-secondary"].into());
+      assert_eq!(runner.hostnames.len(), 2);
+      let result = runner.run();
+      assert!(result.is_ok(), "Result: {:?}", result.red());
+    }
+
     #[test_log::test]
     #[ignore]
     fn should_run_check() {
@@ -238,6 +603,95 @@ mod tests {
     }
   }
 
+  /// Caches the metadata of `github:owner/dotfiles` in `dir`, which avoids resolving the flake with nix
+  fn cache_flake_metadata(dir: &Path) -> color_eyre::Result<String> {
+    let cache_path = dir.join("flake-metadata.json");
+    let mut cache = crate::metadata_cache::MetadataCache::load(&cache_path, Duration::from_secs(60));
+    cache.insert(
+      "github:owner/dotfiles",
+      serde_json::json!({ "url": "github:owner/dotfiles" }),
+      std::time::SystemTime::now(),
+    );
+    cache.save()?;
+    Ok(cache_path.to_str().unwrap().to_string())
+  }
+
+  #[test_log::test]
+  fn should_print_flake_attr_for_hostname() -> color_eyre::Result<()> {
+    use clap::Parser;
+
+    let dir = tempfile::tempdir()?;
+    let cache_path = cache_flake_metadata(dir.path())?;
+    let cli = Cli::parse_from([
+      env!("CARGO_BIN_NAME"),
+      "--flake",
+      "github:owner/dotfiles",
+      "--hostname",
+      "work-laptop",
+      "--cache-flake-metadata",
+      &cache_path,
+      "--print-flake-attr",
+    ]);
+    let runner = NixDarwinRunner::new(&cli)?;
+    let mut stdout = vec![];
+    runner.write_flake_attr(&mut stdout)?;
+    assert_eq!(String::from_utf8(stdout)?, "darwinConfigurations.work-laptop\n");
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn should_write_report() -> color_eyre::Result<()> {
+    use clap::Parser;
+
+    let dir = tempfile::tempdir()?;
+    let cache_path = cache_flake_metadata(dir.path())?;
+    let report_path = dir.path().join("report.json");
+    let cli = Cli::parse_from([
+      env!("CARGO_BIN_NAME"),
+      "--list-generations",
+      "--flake",
+      "github:owner/dotfiles",
+      "--hostname",
+      "work-laptop",
+      "--cache-flake-metadata",
+      &cache_path,
+      "--report",
+      report_path.to_str().unwrap(),
+    ]);
+    let mut runner = NixDarwinRunner::new(&cli)?;
+    // listing the generations of a missing profile succeeds without nix
+    runner.profile = dir.path().join("missing-profile").display().to_string();
+    runner.run()?;
+
+    let report: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&report_path)?)?;
+    let keys: Vec<_> = report.as_object().unwrap().keys().cloned().collect();
+    assert_eq!(keys, [
+      "action",
+      "elapsed_seconds",
+      "error",
+      "flake",
+      "hosts",
+      "new_generation",
+      "old_generation",
+      "out_path",
+      "success"
+    ]);
+    assert_eq!(report["action"], "listgenerations");
+    assert_eq!(report["hosts"], serde_json::json!(["work-laptop"]));
+    assert_eq!(report["flake"], "github:owner/dotfiles");
+    assert_eq!(report["success"], true);
+    assert_eq!(report["error"], serde_json::Value::Null);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn should_not_print_flake_attr_without_flake() {
+    let cli = Cli { print_flake_attr: true, no_flake: true, ..Default::default() };
+    let runner = NixDarwinRunner::new(&cli).unwrap();
+    let err = runner.write_flake_attr(&mut vec![]).unwrap_err();
+    assert_eq!(err.to_string(), "--print-flake-attr requires a flake");
+  }
+
   #[rstest]
   #[case::zsh(clap_complete::Shell::Zsh)]
   #[case::bash(clap_complete::Shell::Bash)]
@@ -246,7 +700,7 @@ mod tests {
   #[case::elvish(clap_complete::Shell::Elvish)]
   fn can_run_completions(#[case] shell: clap_complete::Shell) {
     fn run_completions(shell: clap_complete::Shell) -> color_eyre::Result<()> {
-      let cli = Cli { action: Some(Action::Completions(CompletionArgs { shell })), ..Default::default() };
+      let cli = Cli { action: Some(Action::Completions(CompletionArgs { shell, output: None })), ..Default::default() };
       let runner = NixDarwinRunner::new(&cli)?;
       runner.run()
     }
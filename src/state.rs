@@ -0,0 +1,162 @@
+use std::{
+  env, fs,
+  path::{Path, PathBuf},
+};
+
+use color_eyre::{eyre::eyre, owo_colors::OwoColorize, Section};
+use log::debug;
+
+type Result<T> = color_eyre::Result<T>;
+
+const LAST_BUILD: &str = "last-build";
+const RESULT: &str = "result";
+const NOTES: &str = "notes";
+
+/// Get the directory where darwin-rebuild persists its state, `$XDG_STATE_HOME/darwin-rebuild`
+pub fn state_dir() -> Result<PathBuf> {
+  let state_home = match env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+    Some(dir) => PathBuf::from(dir),
+    None => {
+      let home = env::var_os("HOME").ok_or(eyre!("unable to find the state directory, $HOME is not set"))?;
+      Path::new(&home).join(".local").join("state")
+    },
+  };
+  Ok(state_home.join(env!("CARGO_PKG_NAME")))
+}
+
+/// Persist the out path of the last successful build
+pub fn write_last_build(state_dir: &Path, out_path: &str) -> Result<()> {
+  debug!("Saving last build {} in {:?}", out_path.yellow(), state_dir);
+  fs::create_dir_all(state_dir)?;
+  fs::write(state_dir.join(LAST_BUILD), out_path)?;
+  Ok(())
+}
+
+/// Read the out path of the last successful build
+pub fn read_last_build(state_dir: &Path) -> Result<String> {
+  let file = state_dir.join(LAST_BUILD);
+  debug!("Reading last build from {:?}", file);
+  let out_path = fs::read_to_string(&file)
+    .map_err(|e| eyre!("unable to read the last build from {:?}", file).with_error(|| e))
+    .suggestion("run darwin-rebuild build first")?;
+  Ok(out_path.trim().to_string())
+}
+
+/// Point the `result` symlink of the state directory at `out_path`, replacing it atomically
+pub fn update_result_link(state_dir: &Path, out_path: &str) -> Result<PathBuf> {
+  let link = state_dir.join(RESULT);
+  debug!("Linking {:?} to {}", link, out_path.yellow());
+  fs::create_dir_all(state_dir)?;
+  let tmp_link = state_dir.join(format!(".{}-{}", RESULT, std::process::id()));
+  let _ = fs::remove_file(&tmp_link);
+  std::os::unix::fs::symlink(out_path, &tmp_link)?;
+  fs::rename(&tmp_link, &link)?;
+  Ok(link)
+}
+
+/// The directory holding the generation notes of `profile`, named after its flattened path
+fn notes_dir(state_dir: &Path, profile: &str) -> PathBuf {
+  state_dir.join(NOTES).join(profile.trim_start_matches('/').replace('/', "-"))
+}
+
+/// The file holding the note of a generation, `nix-env` profiles can't carry descriptions
+pub fn generation_note_path(state_dir: &Path, profile: &str, generation: u32) -> PathBuf {
+  notes_dir(state_dir, profile).join(format!("{}.note", generation))
+}
+
+/// Read the note of a generation, `None` when it has none
+pub fn read_generation_note(state_dir: &Path, profile: &str, generation: u32) -> Option<String> {
+  let note = fs::read_to_string(generation_note_path(state_dir, profile, generation)).ok()?;
+  Some(note.trim().to_string()).filter(|note| !note.is_empty())
+}
+
+/// Persist the note of a generation given with `--message`
+pub fn write_generation_note(state_dir: &Path, profile: &str, generation: u32, message: &str) -> Result<()> {
+  let path = generation_note_path(state_dir, profile, generation);
+  debug!("Writing the note of generation {} to {:?}", generation, path);
+  fs::create_dir_all(notes_dir(state_dir, profile))?;
+  fs::write(&path, message).map_err(|e| eyre!("unable to write the note {:?}", path).with_error(|| e))
+}
+
+/// Delete the notes of the generations whose `<profile>-<number>-link` is gone
+pub fn remove_stale_notes(state_dir: &Path, profile: &str) -> Result<()> {
+  let dir = notes_dir(state_dir, profile);
+  if !dir.is_dir() {
+    return Ok(());
+  }
+  for entry in fs::read_dir(&dir)? {
+    let path = entry?.path();
+    let Some(generation) = path.file_name().and_then(|name| name.to_str()?.strip_suffix(".note")) else {
+      continue;
+    };
+    if Path::new(&format!("{}-{}-link", profile, generation)).symlink_metadata().is_err() {
+      debug!("Removing the stale note {:?}", path);
+      fs::remove_file(&path)?;
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_str_eq;
+
+  use super::*;
+
+  #[test_log::test]
+  fn test_last_build_round_trips() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let state_dir = dir.path().join("darwin-rebuild");
+    write_last_build(&state_dir, "/nix/store/x-darwin-system")?;
+    assert_str_eq!(read_last_build(&state_dir)?, "/nix/store/x-darwin-system");
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_update_result_link() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let state_dir = dir.path().join("darwin-rebuild");
+    let link = update_result_link(&state_dir, "/nix/store/x-darwin-system")?;
+    assert_eq!(fs::read_link(&link)?, Path::new("/nix/store/x-darwin-system"));
+
+    update_result_link(&state_dir, "/nix/store/y-darwin-system")?;
+    assert_eq!(fs::read_link(&link)?, Path::new("/nix/store/y-darwin-system"));
+    assert_eq!(fs::read_dir(&state_dir)?.count(), 1);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_generation_note_round_trips() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let state_dir = dir.path().join("darwin-rebuild");
+    assert_eq!(read_generation_note(&state_dir, "/nix/var/nix/profiles/system", 42), None);
+    write_generation_note(&state_dir, "/nix/var/nix/profiles/system", 42, "enable yabai\n")?;
+    assert!(state_dir.join("notes").join("nix-var-nix-profiles-system").join("42.note").is_file());
+    assert_eq!(read_generation_note(&state_dir, "/nix/var/nix/profiles/system", 42).as_deref(), Some("enable yabai"));
+    assert_eq!(read_generation_note(&state_dir, "/nix/var/nix/profiles/system", 41), None);
+    assert_eq!(read_generation_note(&state_dir, "/nix/var/nix/profiles/system-profiles/work", 42), None);
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_remove_stale_notes() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let state_dir = dir.path().join("darwin-rebuild");
+    let profile = dir.path().join("system").display().to_string();
+    std::os::unix::fs::symlink("/nix/store/x-darwin-system", format!("{}-2-link", profile))?;
+    write_generation_note(&state_dir, &profile, 1, "pruned")?;
+    write_generation_note(&state_dir, &profile, 2, "kept")?;
+
+    remove_stale_notes(&state_dir, &profile)?;
+    assert_eq!(read_generation_note(&state_dir, &profile, 1), None);
+    assert_eq!(read_generation_note(&state_dir, &profile, 2).as_deref(), Some("kept"));
+    remove_stale_notes(&dir.path().join("missing"), &profile)?;
+    Ok(())
+  }
+
+  #[test_log::test]
+  fn test_read_last_build_without_build() {
+    let dir = tempfile::tempdir().unwrap();
+    assert!(read_last_build(dir.path()).is_err());
+  }
+}
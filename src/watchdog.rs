@@ -0,0 +1,139 @@
+use std::{
+  io::Read,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+  },
+  thread::JoinHandle,
+  time::{Duration, Instant},
+};
+
+use log::warn;
+
+/// Tracks the time since the last output to detect stalled builds
+#[derive(Debug)]
+struct StallState {
+  last_output: Instant,
+  warned: bool,
+}
+
+impl StallState {
+  fn new(now: Instant) -> Self { Self { last_output: now, warned: false } }
+
+  fn touch(&mut self, now: Instant) {
+    self.last_output = now;
+    self.warned = false;
+  }
+
+  /// Returns how long the build has been quiet when it exceeds `threshold`, only once per quiet period
+  fn poll(&mut self, now: Instant, threshold: Duration) -> Option<Duration> {
+    let quiet = now.saturating_duration_since(self.last_output);
+    if self.warned || quiet < threshold {
+      return None;
+    }
+    self.warned = true;
+    Some(quiet)
+  }
+}
+
+/// Warns when a build hasn't produced any output for a while, it may be stuck fetching
+pub struct StallWatchdog {
+  state: Arc<Mutex<StallState>>,
+  stop: Arc<AtomicBool>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl StallWatchdog {
+  /// Start watching, `None` when `threshold` is zero
+  pub fn spawn(threshold: Duration) -> Option<Self> {
+    if threshold.is_zero() {
+      return None;
+    }
+    let state = Arc::new(Mutex::new(StallState::new(Instant::now())));
+    let stop = Arc::new(AtomicBool::new(false));
+    let handle = {
+      let state = state.clone();
+      let stop = stop.clone();
+      let interval = threshold.min(Duration::from_millis(500));
+      std::thread::spawn(move || {
+        while !stop.load(Ordering::Relaxed) {
+          std::thread::sleep(interval);
+          if let Some(quiet) = state.lock().unwrap().poll(Instant::now(), threshold) {
+            warn!("no build output for {}s…", quiet.as_secs());
+          }
+        }
+      })
+    };
+    Some(Self { state, stop, handle: Some(handle) })
+  }
+
+  /// Record that the build produced some output
+  pub fn touch(&self) { self.state.lock().unwrap().touch(Instant::now()) }
+}
+
+impl Drop for StallWatchdog {
+  fn drop(&mut self) {
+    self.stop.store(true, Ordering::Relaxed);
+    if let Some(handle) = self.handle.take() {
+      let _ = handle.join();
+    }
+  }
+}
+
+/// A reader touching the watchdog whenever data is read
+pub struct WatchedReader<'a, R> {
+  inner: R,
+  watchdog: Option<&'a StallWatchdog>,
+}
+
+impl<'a, R: Read> WatchedReader<'a, R> {
+  pub fn new(inner: R, watchdog: Option<&'a StallWatchdog>) -> Self { Self { inner, watchdog } }
+}
+
+impl<R: Read> Read for WatchedReader<'_, R> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let read = self.inner.read(buf)?;
+    if let Some(watchdog) = self.watchdog {
+      watchdog.touch();
+    }
+    Ok(read)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  #[test_log::test]
+  fn test_stall_state_warns_once_per_quiet_period() {
+    let start = Instant::now();
+    let threshold = Duration::from_secs(60);
+    let mut state = StallState::new(start);
+
+    assert_eq!(state.poll(start + Duration::from_secs(30), threshold), None);
+    assert_eq!(state.poll(start + Duration::from_secs(61), threshold), Some(Duration::from_secs(61)));
+    assert_eq!(state.poll(start + Duration::from_secs(90), threshold), None);
+
+    state.touch(start + Duration::from_secs(100));
+    assert_eq!(state.poll(start + Duration::from_secs(130), threshold), None);
+    assert_eq!(state.poll(start + Duration::from_secs(160), threshold), Some(Duration::from_secs(60)));
+  }
+
+  #[test_log::test]
+  fn test_watchdog_is_disabled_with_zero() {
+    assert!(StallWatchdog::spawn(Duration::ZERO).is_none());
+  }
+
+  #[test_log::test]
+  fn test_watched_reader_touches_watchdog() -> std::io::Result<()> {
+    let watchdog = StallWatchdog::spawn(Duration::from_secs(60)).unwrap();
+    let before = watchdog.state.lock().unwrap().last_output;
+    let mut output = String::new();
+    WatchedReader::new("line\n".as_bytes(), Some(&watchdog)).read_to_string(&mut output)?;
+    assert_eq!(output, "line\n");
+    assert!(watchdog.state.lock().unwrap().last_output > before);
+    Ok(())
+  }
+}